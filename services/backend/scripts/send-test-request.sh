@@ -11,6 +11,9 @@ ID=$(uuidgen || date +%s)
 GUIDANCE_SCALE=7.5
 STEPS=20
 NEG_PROMPT=""
+ASPECT_RATIO=""
+SEED=""
+STYLE=""
 
 # Parse command line arguments
 while [[ $# -gt 0 ]]; do
@@ -23,6 +26,10 @@ while [[ $# -gt 0 ]]; do
       SUBJECT="$2"
       shift 2
       ;;
+    --lane)
+      SUBJECT="meme.request.$2"
+      shift 2
+      ;;
     --prompt)
       PROMPT="$2"
       shift 2
@@ -43,16 +50,32 @@ while [[ $# -gt 0 ]]; do
       NEG_PROMPT="$2"
       shift 2
       ;;
+    --aspect-ratio)
+      ASPECT_RATIO="$2"
+      shift 2
+      ;;
+    --seed)
+      SEED="$2"
+      shift 2
+      ;;
+    --style)
+      STYLE="$2"
+      shift 2
+      ;;
     --help)
       echo "Usage: $0 [options]"
       echo "Options:"
       echo "  --nats-url URL     NATS server URL (default: nats://localhost:4222)"
       echo "  --subject SUBJECT  NATS subject (default: meme.request)"
+      echo "  --lane NAME        Priority lane, same as --subject meme.request.NAME"
       echo "  --prompt TEXT      Image prompt (default: A cat wearing sunglasses on a beach)"
       echo "  --id ID            Request ID (default: generated UUID)"
       echo "  --guidance NUM     Guidance scale (default: 7.5)"
       echo "  --steps NUM        Inference steps (default: 20)"
       echo "  --negative TEXT    Negative prompt (default: empty)"
+      echo "  --aspect-ratio R   Aspect ratio preset: 1:1, 4:3, 3:4, 16:9, 9:16 (default: square)"
+      echo "  --seed NUM         Random seed, to reproduce an earlier image (default: random)"
+      echo "  --style NAME       Style preset, e.g. pixel-art or anime (default: cartoon)"
       echo "  --help             Show this help message"
       exit 0
       ;;
@@ -64,11 +87,22 @@ while [[ $# -gt 0 ]]; do
 done
 
 # Build JSON payload
+EXTRA_FIELDS=""
+if [ -n "$ASPECT_RATIO" ]; then
+  EXTRA_FIELDS="\"aspect_ratio\": \"$ASPECT_RATIO\","
+fi
+if [ -n "$SEED" ]; then
+  EXTRA_FIELDS="$EXTRA_FIELDS \"seed\": $SEED,"
+fi
+if [ -n "$STYLE" ]; then
+  EXTRA_FIELDS="$EXTRA_FIELDS \"style\": \"$STYLE\","
+fi
 if [ -z "$NEG_PROMPT" ]; then
   JSON_PAYLOAD=$(cat <<EOF
 {
   "id": "$ID",
   "prompt": "$PROMPT",
+  $EXTRA_FIELDS
   "guidance_scale": $GUIDANCE_SCALE,
   "num_inference_steps": $STEPS
 }
@@ -79,6 +113,7 @@ else
 {
   "id": "$ID",
   "prompt": "$PROMPT",
+  $EXTRA_FIELDS
   "negative_prompt": "$NEG_PROMPT",
   "guidance_scale": $GUIDANCE_SCALE,
   "num_inference_steps": $STEPS
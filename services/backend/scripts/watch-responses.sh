@@ -55,7 +55,10 @@ process_message() {
   local message="$1"
   local request_id=$(echo "$message" | jq -r '.request_id')
   local prompt=$(echo "$message" | jq -r '.prompt')
-  local image_data=$(echo "$message" | jq -r '.image_data')
+  local image_data=$(echo "$message" | jq -r '.image_data // empty')
+  local image_url=$(echo "$message" | jq -r '.image_url // empty')
+  local image_key=$(echo "$message" | jq -r '.image_key // empty')
+  local image_bucket=$(echo "$message" | jq -r '.image_bucket // empty')
   local timestamp=$(echo "$message" | jq -r '.timestamp')
   
   # Create a sanitized filename from the prompt
@@ -65,8 +68,17 @@ process_message() {
   echo "Received image for request $request_id"
   echo "Prompt: $prompt"
   
-  # Decode base64 and save as PNG
-  echo "$image_data" | base64 -d > "$filename"
+  # Inline responses carry base64 data, stored images are downloaded from their URL
+  if [ -n "$image_data" ]; then
+    echo "$image_data" | base64 -d > "$filename"
+  elif [ -n "$image_url" ]; then
+    curl -sf "$image_url" -o "$filename"
+  elif [ -n "$image_bucket" ]; then
+    nats object get "$image_bucket" "$image_key" --output "$filename" --server="$NATS_URL"
+  else
+    echo "Response has no image data or URL (key: $image_key)"
+    return
+  fi
   
   echo "Saved image to $filename"
   echo "----------------"
@@ -0,0 +1,86 @@
+// ===== REQUEST CANCELLATION =====
+// Clients can cancel a request by publishing its ID to the cancel subject. Every replica keeps
+// a registry of the requests it is currently processing; the replica that owns the request
+// signals the task, which drops its processing future (aborting any in-flight provider call),
+// acks the message and records the cancelled status. Requests that have not been picked up
+// yet are marked cancelled in the status bucket and skipped when they arrive.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::sync::oneshot;
+
+/// Requests currently being processed by this replica, keyed by request ID
+#[derive(Default)]
+pub struct InFlight {
+    tasks: Mutex<HashMap<String, (u64, oneshot::Sender<()>)>>,
+    next_token: AtomicU64,
+}
+
+impl InFlight {
+    /// Registers a request as in flight until the returned guard is dropped
+    pub fn register(self: &Arc<Self>, request_id: &str) -> InFlightGuard {
+        let token = self.next_token.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = oneshot::channel();
+        self.tasks
+            .lock()
+            .expect("in-flight registry lock poisoned")
+            .insert(request_id.to_string(), (token, sender));
+        InFlightGuard {
+            registry: self.clone(),
+            request_id: request_id.to_string(),
+            token,
+            receiver,
+        }
+    }
+
+    /// Signals the task processing `request_id`; returns false if it is not running here
+    pub fn cancel(&self, request_id: &str) -> bool {
+        let task = self
+            .tasks
+            .lock()
+            .expect("in-flight registry lock poisoned")
+            .remove(request_id);
+        match task {
+            Some((_, sender)) => sender.send(()).is_ok(),
+            None => false,
+        }
+    }
+}
+
+/// Registration of one in-flight request, removed from the registry on drop
+pub struct InFlightGuard {
+    registry: Arc<InFlight>,
+    request_id: String,
+    /// Distinguishes this registration from a later one for the same request ID
+    /// (e.g. a redelivery), so dropping a stale guard never removes the newer entry
+    token: u64,
+    receiver: oneshot::Receiver<()>,
+}
+
+impl InFlightGuard {
+    /// Resolves when the request is cancelled; never resolves otherwise
+    pub async fn cancelled(&mut self) {
+        if (&mut self.receiver).await.is_err() {
+            // The sender was replaced by a newer registration, not cancelled
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        let mut tasks = self
+            .registry
+            .tasks
+            .lock()
+            .expect("in-flight registry lock poisoned");
+        if matches!(tasks.get(&self.request_id), Some((token, _)) if *token == self.token) {
+            tasks.remove(&self.request_id);
+        }
+    }
+}
@@ -0,0 +1,181 @@
+// ===== CAPTION RENDERING =====
+// Classic top/bottom meme captions are drawn by the service rather than requested from the
+// diffusion model, which cannot reliably render legible text. Captions are upper-cased,
+// word-wrapped and shrunk until they fit their box, then drawn white with a black outline.
+// The same box layout is reused by the template library for per-template text boxes.
+
+use ab_glyph::{FontArc, PxScale};
+use anyhow::{Context, Result};
+use image::{ImageOutputFormat, Rgba, RgbaImage};
+use std::{io::Cursor, path::Path};
+
+use crate::text;
+
+/// Maximum share of the image height a single top/bottom caption may occupy
+const MAX_CAPTION_HEIGHT: f32 = 0.28;
+
+/// Smallest font size, in pixels, captions are allowed to shrink to
+const MIN_FONT_SIZE: f32 = 12.0;
+
+/// Vertical placement of the text block inside its box
+#[derive(Debug, Clone, Copy)]
+pub enum VerticalAlign {
+    Top,
+    Middle,
+    Bottom,
+}
+
+/// Rectangle that a piece of text is laid out in, plus how it should look
+#[derive(Debug, Clone)]
+pub struct TextBox {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+    /// Starting font size; text shrinks from here until it fits
+    pub max_font_size: f32,
+    pub align: VerticalAlign,
+    pub fill: Rgba<u8>,
+    /// Outline colour, or `None` for plain text
+    pub outline: Option<Rgba<u8>>,
+}
+
+/// Renders top/bottom captions onto generated images
+///
+/// The font is loaded once at startup and shared by every request. Rendering itself is
+/// synchronous and CPU-bound, so callers should run it on a blocking thread.
+#[derive(Clone)]
+pub struct CaptionRenderer {
+    font: FontArc,
+}
+
+impl CaptionRenderer {
+    /// Creates a renderer using the font at `font_path`, or the bundled bold font if unset
+    pub fn new(font_path: Option<&Path>) -> Result<Self> {
+        let font = match font_path {
+            Some(path) => {
+                let bytes = std::fs::read(path)
+                    .with_context(|| format!("Failed to read caption font {}", path.display()))?;
+                FontArc::try_from_vec(bytes)
+                    .with_context(|| format!("Invalid caption font {}", path.display()))?
+            }
+            None => FontArc::new(text::default_font().clone()),
+        };
+        Ok(Self { font })
+    }
+
+    /// Decodes `image_bytes`, draws the given captions and re-encodes the result as PNG
+    pub fn render(
+        &self,
+        image_bytes: &[u8],
+        top_text: Option<&str>,
+        bottom_text: Option<&str>,
+    ) -> Result<Vec<u8>> {
+        let mut image = image::load_from_memory(image_bytes)
+            .context("Failed to decode generated image for captioning")?
+            .to_rgba8();
+
+        let (w, h) = (image.width() as f32, image.height() as f32);
+        let margin = w.min(h) * 0.04;
+        let caption_box = |align| TextBox {
+            x: margin,
+            y: margin,
+            width: w - 2.0 * margin,
+            height: h - 2.0 * margin,
+            max_font_size: h / 9.0,
+            align,
+            fill: Rgba([255, 255, 255, 255]),
+            outline: Some(Rgba([0, 0, 0, 255])),
+        };
+
+        if let Some(top) = top_text {
+            self.draw_text_box(&mut image, top, &caption_box(VerticalAlign::Top));
+        }
+        if let Some(bottom) = bottom_text {
+            self.draw_text_box(&mut image, bottom, &caption_box(VerticalAlign::Bottom));
+        }
+
+        encode_png(&image)
+    }
+
+    /// Lays out upper-cased text inside `text_box` and draws it centred horizontally
+    ///
+    /// Top and bottom aligned boxes are additionally capped at `MAX_CAPTION_HEIGHT` of the
+    /// image so that long captions shrink instead of covering the whole picture.
+    pub fn draw_text_box(&self, image: &mut RgbaImage, caption: &str, text_box: &TextBox) {
+        let caption = caption.trim().to_uppercase();
+        if caption.is_empty() {
+            return;
+        }
+
+        let max_height = match text_box.align {
+            VerticalAlign::Middle => text_box.height,
+            _ => text_box.height.min(image.height() as f32 * MAX_CAPTION_HEIGHT),
+        };
+        let (scale, lines) = self.fit(&caption, text_box.width, max_height, text_box.max_font_size);
+        let line_height = text::line_height(&self.font, scale);
+        let outline_width = (scale.y / 14.0).round().max(1.0) as u32;
+
+        let block_height = lines.len() as f32 * line_height;
+        let mut y = match text_box.align {
+            VerticalAlign::Top => text_box.y,
+            VerticalAlign::Middle => text_box.y + (text_box.height - block_height) / 2.0,
+            VerticalAlign::Bottom => text_box.y + text_box.height - block_height,
+        };
+
+        for line in &lines {
+            let x = text_box.x + (text_box.width - text::text_width(&self.font, scale, line)) / 2.0;
+            match text_box.outline {
+                Some(outline) => text::draw_outlined_text(
+                    image,
+                    &self.font,
+                    scale,
+                    x,
+                    y,
+                    line,
+                    text_box.fill,
+                    outline,
+                    outline_width,
+                ),
+                None => text::draw_text(image, &self.font, scale, x, y, line, text_box.fill),
+            }
+            y += line_height;
+        }
+    }
+
+    /// Finds the largest font size at which the wrapped caption fits inside the box
+    ///
+    /// Starts at `max_size` and shrinks by 10% per step. If even the minimum size does not
+    /// fit, the minimum-size layout is returned and the caption is allowed to overflow.
+    fn fit(
+        &self,
+        caption: &str,
+        max_width: f32,
+        max_height: f32,
+        max_size: f32,
+    ) -> (PxScale, Vec<String>) {
+        let mut size = max_size.max(MIN_FONT_SIZE);
+        loop {
+            let scale = PxScale::from(size);
+            let lines = text::wrap_text(&self.font, scale, caption, max_width);
+            let fits_width = lines
+                .iter()
+                .all(|line| text::text_width(&self.font, scale, line) <= max_width);
+            let fits_height = lines.len() as f32 * text::line_height(&self.font, scale) <= max_height;
+
+            if (fits_width && fits_height) || size <= MIN_FONT_SIZE {
+                return (scale, lines);
+            }
+            size = (size * 0.9).max(MIN_FONT_SIZE);
+        }
+    }
+}
+
+/// Encodes an RGBA image as PNG bytes
+pub fn encode_png(image: &RgbaImage) -> Result<Vec<u8>> {
+    let mut png = Cursor::new(Vec::new());
+    image
+        .write_to(&mut png, ImageOutputFormat::Png)
+        .context("Failed to encode image as PNG")?;
+    Ok(png.into_inner())
+}
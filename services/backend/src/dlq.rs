@@ -0,0 +1,478 @@
+// ===== DEAD-LETTER QUEUE =====
+// A request that JetStream has delivered `max_deliver` times without an ack (the replica
+// crashed, hung past the ack wait, or kept being interrupted) is never delivered again. The
+// server announces this with a MAX_DELIVERIES advisory. Advisories are plain NATS messages,
+// so they are captured in a small work-queue stream that keeps them while no replica is
+// running (scaled to zero, mid-rollout); a durable consumer on it hands each one to a single
+// replica, which copies the original payload together with the failure details onto a
+// dead-letter stream, removes it from the work queue and sends the client a final error.
+// Dead-lettered requests can be listed and replayed with the `dlq` subcommand.
+
+use anyhow::{bail, Context, Result};
+use async_nats::{
+    jetstream::{self, consumer::pull, stream::LastRawMessageErrorKind, AckKind},
+    HeaderMap,
+};
+use bytes::Bytes;
+use clap::Subcommand;
+use futures::StreamExt;
+use serde::Deserialize;
+use std::{sync::Arc, time::Duration};
+use tokio::sync::Mutex;
+use tracing::{debug, info, warn};
+
+use crate::{provisioning, status::JobState, AppState, Config, Delivery, MemeRequest};
+
+/// Durable consumer sharing the captured advisories out between replicas
+const ADVISORY_CONSUMER: &str = "meme-generator-dlq";
+
+/// Wait before an advisory that could not be handled is tried again
+const ADVISORY_RETRY_DELAY: Duration = Duration::from_secs(10);
+
+/// Headers carrying the failure details on dead-lettered messages
+const HEADER_REASON: &str = "Meme-Dlq-Reason";
+const HEADER_DELIVERIES: &str = "Meme-Dlq-Deliveries";
+const HEADER_STREAM: &str = "Meme-Dlq-Stream";
+const HEADER_STREAM_SEQ: &str = "Meme-Dlq-Stream-Seq";
+const HEADER_SUBJECT: &str = "Meme-Dlq-Subject";
+const HEADER_CONSUMER: &str = "Meme-Dlq-Consumer";
+const HEADER_FAILED_AT: &str = "Meme-Dlq-Failed-At";
+const HEADER_LAST_ERROR: &str = "Meme-Dlq-Last-Error";
+
+/// Inspect and replay dead-lettered requests
+#[derive(Subcommand, Debug, Clone)]
+pub enum DlqCommand {
+    /// List dead-lettered requests, oldest first
+    List {
+        /// Maximum number of entries to show
+        #[clap(long, default_value = "20")]
+        limit: usize,
+    },
+    /// Publish dead-lettered requests to the request subject again
+    ///
+    /// Replayed entries are removed from the dead-letter stream.
+    Replay {
+        /// Dead-letter sequence numbers to replay, as shown by `dlq list`
+        #[clap(required_unless_present = "all")]
+        sequences: Vec<u64>,
+
+        /// Replay every dead-lettered request
+        #[clap(long, conflicts_with = "sequences")]
+        all: bool,
+    },
+}
+
+/// MAX_DELIVERIES advisory published by the server
+///
+/// Only the fields we use are listed.
+#[derive(Debug, Deserialize)]
+struct MaxDeliveriesAdvisory {
+    stream: String,
+    consumer: String,
+    stream_seq: u64,
+    deliveries: u64,
+}
+
+/// A request on the dead-letter stream
+pub struct DeadLetter {
+    /// Sequence of the entry in the dead-letter stream
+    pub sequence: u64,
+    /// Original request payload, unchanged
+    pub payload: Bytes,
+    /// Subject (and so lane) the request was originally published to
+    pub subject: Option<String>,
+    pub deliveries: Option<u64>,
+    pub failed_at: Option<u64>,
+    pub last_error: Option<String>,
+}
+
+impl DeadLetter {
+    fn new(sequence: u64, headers: Option<&HeaderMap>, payload: Bytes) -> Self {
+        let header = |name: &str| {
+            headers
+                .and_then(|headers| headers.get(name))
+                .map(|value| value.to_string())
+        };
+        Self {
+            sequence,
+            deliveries: header(HEADER_DELIVERIES).and_then(|value| value.parse().ok()),
+            failed_at: header(HEADER_FAILED_AT).and_then(|value| value.parse().ok()),
+            last_error: header(HEADER_LAST_ERROR),
+            subject: header(HEADER_SUBJECT),
+            payload,
+        }
+    }
+
+    fn request(&self) -> Option<MemeRequest> {
+        serde_json::from_slice(&self.payload).ok()
+    }
+}
+
+/// Writes and reads dead-lettered requests on a dedicated stream
+pub struct DeadLetterQueue {
+    js: jetstream::Context,
+    stream: jetstream::stream::Stream,
+    subject: String,
+}
+
+impl DeadLetterQueue {
+    /// Opens the dead-letter stream, creating or reconciling it as needed
+    pub async fn new(js: &jetstream::Context, config: &Config) -> Result<Self> {
+        let stream_config = jetstream::stream::Config {
+            name: config.dlq_stream.clone(),
+            description: Some("Meme requests that exhausted their deliveries".to_string()),
+            subjects: vec![config.dlq_subject.clone()],
+            storage: jetstream::stream::StorageType::File,
+            retention: jetstream::stream::RetentionPolicy::Limits,
+            max_age: Duration::from_secs(config.dlq_max_age),
+            ..Default::default()
+        };
+        let stream = provisioning::ensure_stream(js, stream_config, config.reconcile_mode).await?;
+
+        Ok(Self {
+            js: js.clone(),
+            stream,
+            subject: config.dlq_subject.clone(),
+        })
+    }
+
+    /// Copies `payload` onto the dead-letter stream and waits until it is stored
+    async fn add(
+        &self,
+        payload: Bytes,
+        subject: &str,
+        advisory: &MaxDeliveriesAdvisory,
+        last_error: Option<&str>,
+    ) -> Result<u64> {
+        let mut headers = HeaderMap::new();
+        headers.insert(HEADER_REASON, "max_deliveries");
+        headers.insert(HEADER_DELIVERIES, advisory.deliveries.to_string());
+        headers.insert(HEADER_STREAM, advisory.stream.as_str());
+        headers.insert(HEADER_STREAM_SEQ, advisory.stream_seq.to_string());
+        headers.insert(HEADER_SUBJECT, subject);
+        headers.insert(HEADER_CONSUMER, advisory.consumer.as_str());
+        headers.insert(HEADER_FAILED_AT, crate::status::now_secs().to_string());
+        if let Some(error) = last_error {
+            // Header values cannot span lines
+            headers.insert(HEADER_LAST_ERROR, error.replace(['\r', '\n'], " "));
+        }
+
+        let ack = self
+            .js
+            .publish_with_headers(self.subject.clone(), headers, payload)
+            .await
+            .context("Failed to publish dead letter")?
+            .await
+            .context("Dead letter was not stored")?;
+        Ok(ack.sequence)
+    }
+
+    /// Returns the entry at `sequence`, or `None` if it was removed or expired
+    pub async fn get(&self, sequence: u64) -> Result<Option<DeadLetter>> {
+        let message = match self.stream.get_raw_message(sequence).await {
+            Ok(message) => message,
+            Err(e) if e.kind() == LastRawMessageErrorKind::NoMessageFound => return Ok(None),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to read dead letter {}", sequence))
+            }
+        };
+
+        Ok(Some(DeadLetter::new(
+            sequence,
+            Some(&message.headers),
+            message.payload,
+        )))
+    }
+
+    /// Returns up to `limit` entries, oldest first
+    ///
+    /// The entries are read with an ordered consumer, so gaps left by replayed or expired
+    /// entries cost nothing.
+    pub async fn list(&mut self, limit: usize) -> Result<Vec<DeadLetter>> {
+        let state = self
+            .stream
+            .info()
+            .await
+            .context("Failed to read dead-letter stream info")?
+            .state
+            .clone();
+
+        let mut entries = Vec::new();
+        if state.messages == 0 || limit == 0 {
+            return Ok(entries);
+        }
+        let mut messages = self
+            .stream
+            .create_consumer(pull::OrderedConfig::default())
+            .await
+            .context("Failed to create dead-letter reader")?
+            .messages()
+            .await
+            .context("Failed to read dead letters")?;
+        while let Some(message) = messages.next().await {
+            let message = message.context("Failed to read dead letter")?;
+            let info = message
+                .info()
+                .map_err(|e| anyhow::anyhow!("Invalid dead letter metadata: {}", e))?;
+            let (sequence, pending) = (info.stream_sequence, info.pending);
+            entries.push(DeadLetter::new(
+                sequence,
+                message.headers.as_ref(),
+                message.payload.clone(),
+            ));
+            // Entries added while listing are left for the next run
+            if entries.len() >= limit || pending == 0 || sequence >= state.last_sequence {
+                break;
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Removes the entry at `sequence`
+    pub async fn remove(&self, sequence: u64) -> Result<()> {
+        self.stream
+            .delete_message(sequence)
+            .await
+            .with_context(|| format!("Failed to delete dead letter {}", sequence))?;
+        Ok(())
+    }
+}
+
+/// Opens the stream capturing MAX_DELIVERIES advisories for our lane consumers and the
+/// durable consumer replicas read them from, creating or reconciling both as needed
+pub async fn advisory_consumer(
+    js: &jetstream::Context,
+    config: &Config,
+) -> Result<jetstream::consumer::Consumer<pull::Config>> {
+    let subjects = config
+        .request_lanes
+        .iter()
+        .map(|lane| {
+            format!(
+                "$JS.EVENT.ADVISORY.CONSUMER.MAX_DELIVERIES.{}.{}",
+                config.nats_stream,
+                config.lane_consumer(&lane.name)
+            )
+        })
+        .collect();
+    let stream_config = jetstream::stream::Config {
+        name: config.dlq_advisory_stream.clone(),
+        description: Some("Max delivery advisories waiting to be dead-lettered".to_string()),
+        subjects,
+        storage: jetstream::stream::StorageType::File,
+        retention: jetstream::stream::RetentionPolicy::WorkQueue,
+        max_age: Duration::from_secs(config.dlq_max_age),
+        ..Default::default()
+    };
+    let stream = provisioning::ensure_stream(js, stream_config, config.reconcile_mode).await?;
+
+    let consumer_config = pull::Config {
+        durable_name: Some(ADVISORY_CONSUMER.to_string()),
+        deliver_policy: jetstream::consumer::DeliverPolicy::All,
+        ack_policy: jetstream::consumer::AckPolicy::Explicit,
+        ack_wait: Duration::from_secs(60),
+        ..Default::default()
+    };
+    provisioning::ensure_consumer(&stream, consumer_config, config.reconcile_mode).await
+}
+
+/// Dead-letters requests as captured MAX_DELIVERIES advisories arrive
+///
+/// An advisory is only acked once its request was dead-lettered, so advisories published
+/// while no replica was running, or whose handling failed, are handled later.
+pub async fn process_advisories(
+    state: Arc<Mutex<AppState>>,
+    advisories: jetstream::consumer::Consumer<pull::Config>,
+    requests: jetstream::stream::Stream,
+    dead_letters: Arc<DeadLetterQueue>,
+) -> Result<()> {
+    let mut messages = advisories
+        .messages()
+        .await
+        .context("Failed to read max delivery advisories")?;
+    info!("Dead-lettering requests from captured max delivery advisories");
+
+    while let Some(message) = messages.next().await {
+        let message = match message {
+            Ok(message) => message,
+            Err(e) => {
+                warn!("Failed to receive max delivery advisory: {}", e);
+                continue;
+            }
+        };
+        let ack = match serde_json::from_slice::<MaxDeliveriesAdvisory>(&message.payload) {
+            Ok(advisory) => match dead_letter(&state, &requests, &dead_letters, &advisory).await {
+                Ok(()) => AckKind::Ack,
+                Err(e) => {
+                    warn!(
+                        stream_seq = advisory.stream_seq,
+                        "Failed to dead-letter request, retrying: {:#}", e
+                    );
+                    AckKind::Nak(Some(ADVISORY_RETRY_DELAY))
+                }
+            },
+            Err(e) => {
+                warn!("Ignoring malformed max delivery advisory: {}", e);
+                AckKind::Term
+            }
+        };
+        if let Err(e) = message.ack_with(ack).await {
+            warn!("Failed to ack max delivery advisory: {}", e);
+        }
+    }
+
+    Ok(())
+}
+
+/// Moves one request from the work queue to the dead-letter stream and tells the client
+async fn dead_letter(
+    state: &Arc<Mutex<AppState>>,
+    requests: &jetstream::stream::Stream,
+    dead_letters: &DeadLetterQueue,
+    advisory: &MaxDeliveriesAdvisory,
+) -> Result<()> {
+    let message = match requests.get_raw_message(advisory.stream_seq).await {
+        Ok(message) => message,
+        Err(e) if e.kind() == LastRawMessageErrorKind::NoMessageFound => {
+            // The last attempt finished after all and acked the message
+            debug!(
+                stream_seq = advisory.stream_seq,
+                "Request already left the work queue"
+            );
+            return Ok(());
+        }
+        Err(e) => return Err(e).context("Failed to read request from the work queue"),
+    };
+
+    let request = serde_json::from_slice::<MemeRequest>(&message.payload)
+        .ok()
+        .map(|request| MemeRequest {
+            delivery: Some(Delivery {
+                stream: advisory.stream.clone(),
+                sequence: advisory.stream_seq,
+            }),
+            ..request
+        });
+    let last_error = match &request {
+        Some(request) => {
+            let status = state.lock().await.status.clone();
+            status
+                .get(&request.id)
+                .await
+                .ok()
+                .flatten()
+                .and_then(|status| status.error)
+        }
+        None => None,
+    };
+
+    // Store the copy before deleting the original so the request is never lost
+    let sequence = dead_letters
+        .add(
+            message.payload.clone(),
+            message.subject.as_str(),
+            advisory,
+            last_error.as_deref(),
+        )
+        .await?;
+    requests
+        .delete_message(advisory.stream_seq)
+        .await
+        .context("Failed to delete request from the work queue")?;
+    metrics::counter!("meme_generator_dead_lettered_total", 1);
+
+    let Some(request) = request else {
+        warn!(
+            dlq_seq = sequence,
+            "Dead-lettered a request that could not be parsed"
+        );
+        return Ok(());
+    };
+    warn!(
+        request_id = %request.id,
+        dlq_seq = sequence,
+        "Request failed after {} deliveries, moved to the dead-letter stream", advisory.deliveries
+    );
+
+    let error = match last_error {
+        Some(last_error) => format!(
+            "Request failed after {} attempts: {}",
+            advisory.deliveries, last_error
+        ),
+        None => format!("Request failed after {} attempts", advisory.deliveries),
+    };
+    crate::update_status(
+        state,
+        &request.id,
+        JobState::Failed,
+        Some(advisory.deliveries),
+        Some(error.clone()),
+    )
+    .await;
+    crate::send_error_response(state, &request, &anyhow::anyhow!(error)).await
+}
+
+/// Runs a `dlq` subcommand against the configured NATS server
+pub async fn run(config: &Config, command: DlqCommand) -> Result<()> {
+    let nats = async_nats::connect(&config.nats_url)
+        .await
+        .with_context(|| format!("Failed to connect to NATS at {}", config.nats_url))?;
+    let js = jetstream::new(nats.clone());
+    let mut dead_letters = DeadLetterQueue::new(&js, config).await?;
+
+    match command {
+        DlqCommand::List { limit } => {
+            let entries = dead_letters.list(limit).await?;
+            if entries.is_empty() {
+                println!("No dead-lettered requests in {}", config.dlq_stream);
+            }
+            for entry in entries {
+                let request = entry.request();
+                println!(
+                    "{}\t{}\tfailed_at={}\tdeliveries={}\tprompt={:?}\terror={:?}",
+                    entry.sequence,
+                    request.as_ref().map_or("<unparseable>", |r| r.id.as_str()),
+                    entry.failed_at.map_or("-".to_string(), |t| t.to_string()),
+                    entry.deliveries.map_or("-".to_string(), |d| d.to_string()),
+                    request.as_ref().map_or("", |r| r.prompt.as_str()),
+                    entry.last_error.as_deref().unwrap_or(""),
+                );
+            }
+        }
+        DlqCommand::Replay { sequences, all } => {
+            let entries = if all {
+                dead_letters.list(usize::MAX).await?
+            } else {
+                let mut entries = Vec::new();
+                for sequence in sequences {
+                    match dead_letters.get(sequence).await? {
+                        Some(entry) => entries.push(entry),
+                        None => bail!("No dead-lettered request with sequence {}", sequence),
+                    }
+                }
+                entries
+            };
+
+            // Each request goes back to the lane it was originally published to
+            for entry in &entries {
+                let subject = entry
+                    .subject
+                    .clone()
+                    .unwrap_or_else(|| config.request_subject.clone());
+                js.publish(subject.clone(), entry.payload.clone())
+                    .await
+                    .context("Failed to replay request")?
+                    .await
+                    .context("Replayed request was not stored")?;
+                dead_letters.remove(entry.sequence).await?;
+                println!("Replayed dead letter {} to {}", entry.sequence, subject);
+            }
+            println!("Replayed {} request(s)", entries.len());
+        }
+    }
+
+    nats.flush()
+        .await
+        .context("Failed to flush NATS connection")?;
+    Ok(())
+}
@@ -0,0 +1,206 @@
+// ===== FAIR QUEUING =====
+// Requests waiting on a replica are kept in one sub-queue per user and handed out with
+// deficit round-robin: every active user gets the same budget of generation cost per round,
+// so among the pulled requests a user who floods the form only delays their own. This is
+// not fairness across the stream: the stream stays in arrival order, so a flood longer than
+// the pulled window still queues ahead of later requests. The per-user rate limits are the
+// real protection, as they keep such a flood short. Requests without a user or session ID
+// fall back to the client's reply token, and failing that are queued on their own.
+
+use std::collections::{HashMap, VecDeque};
+
+use crate::MemeRequest;
+
+/// Cost budget each user receives per round
+///
+/// Must be at least the highest request cost so every user can be served in one round.
+const QUANTUM: u32 = 2;
+
+/// Identity requests are grouped by for fair scheduling
+pub fn fairness_key(request: &MemeRequest) -> String {
+    client_key(request).unwrap_or_else(|| format!("request:{}", request.id))
+}
+
+/// Best available identity of the client that sent a request, if it carries one
+pub fn client_key(request: &MemeRequest) -> Option<String> {
+    if let Some(user_id) = &request.user_id {
+        Some(format!("user:{}", user_id))
+    } else if let Some(session_id) = &request.session_id {
+        Some(format!("session:{}", session_id))
+    } else {
+        request
+            .reply_to
+            .as_ref()
+            .map(|reply_to| format!("client:{}", reply_to))
+    }
+}
+
+/// Relative generation cost of a request
+///
+/// Quality images take noticeably longer than fast or template ones, so they use up more of
+/// the user's budget.
+pub fn request_cost(request: &MemeRequest) -> u32 {
+    if request.template.is_none() && !request.fast_mode {
+        2
+    } else {
+        1
+    }
+}
+
+/// Per-user FIFO queues served with deficit round-robin
+pub struct FairQueue<T> {
+    queues: HashMap<String, VecDeque<(u32, T)>>,
+    /// Users with queued items, in round-robin order; the front user is being served
+    active: VecDeque<String>,
+    deficits: HashMap<String, u32>,
+    len: usize,
+}
+
+impl<T> Default for FairQueue<T> {
+    fn default() -> Self {
+        Self {
+            queues: HashMap::new(),
+            active: VecDeque::new(),
+            deficits: HashMap::new(),
+            len: 0,
+        }
+    }
+}
+
+impl<T> FairQueue<T> {
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Queues `item` for `key`; users joining get a full budget for their first turn
+    pub fn push(&mut self, key: String, cost: u32, item: T) {
+        let queue = self.queues.entry(key.clone()).or_default();
+        if queue.is_empty() {
+            self.active.push_back(key.clone());
+            self.deficits.insert(key, QUANTUM);
+        }
+        queue.push_back((cost.min(QUANTUM), item));
+        self.len += 1;
+    }
+
+    /// Takes the next item in deficit round-robin order
+    pub fn pop(&mut self) -> Option<T> {
+        loop {
+            let key = self.active.front()?.clone();
+            let queue = self.queues.get_mut(&key)?;
+            let deficit = self.deficits.entry(key.clone()).or_default();
+            let cost = queue.front().map(|(cost, _)| *cost)?;
+
+            if *deficit < cost {
+                // Turn over - the user gets a new budget when it comes round again
+                *deficit += QUANTUM;
+                self.active.rotate_left(1);
+                continue;
+            }
+
+            *deficit -= cost;
+            let (_, item) = queue.pop_front()?;
+            if queue.is_empty() {
+                self.queues.remove(&key);
+                self.deficits.remove(&key);
+                self.active.pop_front();
+            }
+            self.len -= 1;
+            return Some(item);
+        }
+    }
+
+    /// Iterates over every queued item
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.queues
+            .values()
+            .flat_map(|queue| queue.iter().map(|(_, item)| item))
+    }
+
+    /// Removes and returns every queued item
+    pub fn drain(&mut self) -> Vec<T> {
+        self.active.clear();
+        self.deficits.clear();
+        self.len = 0;
+        self.queues
+            .drain()
+            .flat_map(|(_, queue)| queue.into_iter().map(|(_, item)| item))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn drain_order(queue: &mut FairQueue<&'static str>) -> Vec<&'static str> {
+        std::iter::from_fn(|| queue.pop()).collect()
+    }
+
+    #[test]
+    fn flooding_user_takes_turns_with_others() {
+        let mut queue = FairQueue::default();
+        for item in ["a1", "a2", "a3", "a4"] {
+            queue.push("a".to_string(), 1, item);
+        }
+        queue.push("b".to_string(), 1, "b1");
+        queue.push("c".to_string(), 1, "c1");
+
+        assert_eq!(queue.len(), 6);
+        assert_eq!(drain_order(&mut queue), ["a1", "a2", "b1", "c1", "a3", "a4"]);
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn quality_requests_use_up_the_whole_turn() {
+        let mut queue = FairQueue::default();
+        queue.push("a".to_string(), 2, "a1");
+        queue.push("a".to_string(), 2, "a2");
+        queue.push("b".to_string(), 1, "b1");
+        queue.push("b".to_string(), 1, "b2");
+        queue.push("b".to_string(), 1, "b3");
+
+        assert_eq!(drain_order(&mut queue), ["a1", "b1", "b2", "a2", "b3"]);
+    }
+
+    #[test]
+    fn costs_above_the_quantum_are_capped() {
+        let mut queue = FairQueue::default();
+        queue.push("a".to_string(), 10, "a1");
+        queue.push("b".to_string(), 10, "b1");
+        queue.push("a".to_string(), 10, "a2");
+
+        assert_eq!(drain_order(&mut queue), ["a1", "b1", "a2"]);
+    }
+
+    #[test]
+    fn returning_user_starts_with_a_full_budget() {
+        let mut queue = FairQueue::default();
+        queue.push("a".to_string(), 1, "a1");
+        assert_eq!(queue.pop(), Some("a1"));
+        assert_eq!(queue.pop(), None);
+
+        queue.push("b".to_string(), 2, "b1");
+        queue.push("a".to_string(), 1, "a2");
+        queue.push("a".to_string(), 1, "a3");
+        assert_eq!(drain_order(&mut queue), ["b1", "a2", "a3"]);
+    }
+
+    #[test]
+    fn drain_empties_every_queue() {
+        let mut queue = FairQueue::default();
+        queue.push("a".to_string(), 1, "a1");
+        queue.push("b".to_string(), 2, "b1");
+        queue.push("a".to_string(), 1, "a2");
+
+        let mut drained = queue.drain();
+        drained.sort();
+        assert_eq!(drained, ["a1", "a2", "b1"]);
+        assert!(queue.is_empty());
+        assert_eq!(queue.pop(), None);
+    }
+}
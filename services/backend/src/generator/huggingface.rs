@@ -0,0 +1,338 @@
+// ===== HUGGING FACE PROVIDER =====
+// Calls the Hugging Face inference API, which returns the encoded image directly in the
+// response body. Models come from the `ModelRegistry`: a request uses the model it asked for,
+// or its class's (quality, fast) chain, skipping models that cannot produce the requested
+// size, so that the rest of the service only deals with provider-agnostic parameters.
+// Every attempt goes through the shared upstream limiter, failed attempts are retried
+// according to the `RetryPolicy`, and each endpoint has its own circuit breaker. When a
+// model is down, is loading or has its circuit open, the next model in the chain is used
+// straight away; only the last model in the chain spends the whole retry budget.
+
+use anyhow::{bail, Context, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use serde::{Deserialize, Serialize};
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use tracing::{debug, error, info, warn};
+use uuid::Uuid;
+
+use super::limiter::UpstreamLimiter;
+use super::resilience::{parse_retry_after, CircuitBreaker, RetryPolicy, UpstreamError};
+use super::{GeneratedImage, GenerationParams, ImageGenerator, ModelClass, SamplingParams};
+use crate::models::{ImageSize, ModelRegistry, ModelSpec};
+use crate::Config;
+
+/// Request format for the Hugging Face API
+///
+/// Structured according to the Hugging Face inference API requirements.
+/// The inputs field contains the prompt text, while parameters can specify
+/// additional generation options like image dimensions.
+#[derive(Debug, Serialize)]
+struct HuggingFaceRequest {
+    inputs: String,
+    parameters: Option<serde_json::Value>,
+}
+
+/// Picks a seed for a request that did not ask for one, so the image can be reproduced
+fn random_seed() -> u32 {
+    Uuid::new_v4().as_u128() as u32
+}
+
+/// Error body returned by Hugging Face, e.g. while a model is loading
+#[derive(Debug, Deserialize)]
+struct HuggingFaceError {
+    /// Seconds until the model is expected to be loaded
+    estimated_time: Option<f64>,
+}
+
+/// A registered model and the circuit breaker guarding its endpoint
+struct Endpoint {
+    spec: Arc<ModelSpec>,
+    breaker: CircuitBreaker,
+}
+
+impl Endpoint {
+    fn new(spec: Arc<ModelSpec>, config: &Config) -> Self {
+        let breaker = CircuitBreaker::new(
+            spec.name.clone(),
+            config.circuit_breaker_threshold,
+            Duration::from_secs(config.circuit_breaker_cooldown),
+        );
+        Self { spec, breaker }
+    }
+}
+
+/// Image generator backed by the Hugging Face inference API
+pub struct HuggingFaceGenerator {
+    /// HTTP client pre-configured with timeout and authorization headers
+    client: reqwest::Client,
+    /// Models and the chains they are tried in
+    models: Arc<ModelRegistry>,
+    /// Every registered model by name; a model used by several classes shares one breaker
+    endpoints: HashMap<String, Arc<Endpoint>>,
+    /// Cluster-wide limits on calls made with this token
+    limiter: UpstreamLimiter,
+    retry: RetryPolicy,
+}
+
+impl HuggingFaceGenerator {
+    /// Builds the HTTP client and a circuit breaker for every registered model
+    pub fn new(
+        config: &Config,
+        models: Arc<ModelRegistry>,
+        limiter: UpstreamLimiter,
+    ) -> Result<Self> {
+        let api_token = config
+            .hf_api_token
+            .as_deref()
+            .context("HF_API_TOKEN is required when using the huggingface image provider")?;
+
+        // Create HTTP client for Hugging Face API - Pre-configured with authentication
+        // We use a single client for connection pooling and efficient resource usage
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert(
+            "Authorization",
+            format!("Bearer {}", api_token.trim())
+                .parse()
+                .context("Failed to parse authorization header")?,
+        );
+
+        // Configure with a long timeout since image generation can take time
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_secs(300))
+            .default_headers(headers)
+            .build()?;
+
+        let endpoints = models
+            .models()
+            .map(|spec| {
+                let endpoint = Arc::new(Endpoint::new(spec.clone(), config));
+                (spec.name.clone(), endpoint)
+            })
+            .collect();
+        let generator = Self {
+            client,
+            models,
+            endpoints,
+            limiter,
+            retry: RetryPolicy::new(config),
+        };
+
+        for class in [ModelClass::Quality, ModelClass::Fast] {
+            let models: Vec<&str> = generator
+                .models
+                .chain(class)
+                .iter()
+                .map(|spec| spec.name.as_str())
+                .collect();
+            info!(
+                "Models for {} requests: {}",
+                class.as_str(),
+                models.join(" -> ")
+            );
+        }
+        Ok(generator)
+    }
+
+    /// Models tried, in order, for a generation
+    fn chain(&self, params: &GenerationParams) -> Result<Vec<Arc<Endpoint>>> {
+        let size = ImageSize {
+            width: params.width,
+            height: params.height,
+        };
+        let specs = self
+            .models
+            .candidates(params.class, params.model.as_deref(), size)?;
+        Ok(specs
+            .iter()
+            .filter_map(|spec| self.endpoints.get(&spec.name).cloned())
+            .collect())
+    }
+
+    /// Calls one model, retrying failures according to the retry policy
+    ///
+    /// With `has_fallback` set, a model that is loading, timing out or failing with a `5xx` is
+    /// given up on straight away instead of being retried, since the next model can likely
+    /// answer sooner. Throttling is still retried, as it applies to the whole account.
+    async fn generate_with(
+        &self,
+        endpoint: &Endpoint,
+        request: &HuggingFaceRequest,
+        has_fallback: bool,
+    ) -> Result<Bytes> {
+        let mut retries = 0;
+        loop {
+            // Fail fast while the endpoint is known to be down
+            endpoint.breaker.check()?;
+
+            let error = match self
+                .limiter
+                .run(self.name(), self.call(&endpoint.spec, request))
+                .await
+            {
+                Ok(bytes) => {
+                    endpoint.breaker.record_success();
+                    return Ok(bytes);
+                }
+                Err(error) => error,
+            };
+            endpoint.breaker.record_failure(&error);
+            if has_fallback && (error.loading || error.is_outage()) {
+                return Err(error.into());
+            }
+
+            retries += 1;
+            let Some(delay) = self.retry.delay(retries, &error) else {
+                return Err(error.into());
+            };
+            warn!(
+                model = %endpoint.spec.name,
+                "{} - retrying in {:.1}s ({}/{})",
+                error,
+                delay.as_secs_f64(),
+                retries,
+                self.retry.max_retries
+            );
+            metrics::counter!("meme_generator_upstream_retries_total", 1, "endpoint" => endpoint.spec.name.clone());
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Makes a single call to a model endpoint
+    async fn call(
+        &self,
+        spec: &ModelSpec,
+        request: &HuggingFaceRequest,
+    ) -> Result<Bytes, UpstreamError> {
+        debug!("Sending request to Hugging Face API: {}", spec.url);
+
+        let response = self
+            .client
+            .post(&spec.url)
+            .timeout(Duration::from_secs(spec.timeout_secs))
+            .json(request)
+            .send()
+            .await
+            .map_err(|e| UpstreamError {
+                status: None,
+                retry_after: None,
+                loading: false,
+                message: format!("Failed to send request to Hugging Face API: {}", e),
+            })?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let retry_after = response
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|value| value.to_str().ok())
+                .and_then(parse_retry_after);
+            let error_text = response
+                .text()
+                .await
+                .unwrap_or_else(|_| "Unknown error".to_string());
+            error!(
+                status_code = %status.as_u16(),
+                "Hugging Face API error: {}",
+                error_text
+            );
+
+            // A loading model answers 503 with the expected time until it is ready
+            let estimated_time = serde_json::from_str::<HuggingFaceError>(&error_text)
+                .ok()
+                .and_then(|body| body.estimated_time)
+                .filter(|secs| secs.is_finite() && *secs >= 0.0)
+                .map(Duration::from_secs_f64);
+            return Err(UpstreamError {
+                status: Some(status),
+                retry_after: retry_after.or(estimated_time),
+                loading: estimated_time.is_some(),
+                message: format!("Hugging Face API error ({}): {}", status, error_text),
+            });
+        }
+
+        response.bytes().await.map_err(|e| UpstreamError {
+            status: None,
+            retry_after: None,
+            loading: false,
+            message: format!("Failed to get image bytes from response: {}", e),
+        })
+    }
+}
+
+#[async_trait]
+impl ImageGenerator for HuggingFaceGenerator {
+    fn name(&self) -> &'static str {
+        "huggingface"
+    }
+
+    async fn generate(&self, prompt: &str, params: &GenerationParams) -> Result<GeneratedImage> {
+        debug!("Full prompt sent to API: {}", prompt);
+
+        // Every model in the chain gets the same seed
+        let sampling = SamplingParams {
+            seed: Some(params.sampling.seed.unwrap_or_else(random_seed)),
+            ..params.sampling.clone()
+        };
+        let serde_json::Value::Object(overrides) = serde_json::to_value(&sampling)? else {
+            bail!("Sampling parameters did not serialize to an object");
+        };
+
+        // Try the models in order until one produces an image
+        let chain = self.chain(params)?;
+        let mut last_error = None;
+        for (index, endpoint) in chain.iter().enumerate() {
+            let next = chain.get(index + 1);
+
+            // The model's own defaults go first so the request's settings and size win
+            let mut parameters = endpoint.spec.parameters.clone();
+            parameters.extend(overrides.clone());
+            parameters.insert("width".to_string(), params.width.into());
+            parameters.insert("height".to_string(), params.height.into());
+
+            // Report the model defaults that were used too, so responses are complete
+            let used = serde_json::from_value(parameters.clone().into()).unwrap_or_else(|e| {
+                warn!(model = %endpoint.spec.name, "Unexpected model parameters: {}", e);
+                sampling.clone()
+            });
+            let hf_request = HuggingFaceRequest {
+                inputs: prompt.to_string(),
+                parameters: Some(parameters.into()),
+            };
+
+            match self
+                .generate_with(endpoint, &hf_request, next.is_some())
+                .await
+            {
+                Ok(bytes) => {
+                    metrics::histogram!(
+                        "meme_generator_generation_cost",
+                        endpoint.spec.cost,
+                        "model" => endpoint.spec.name.clone()
+                    );
+                    return Ok(GeneratedImage {
+                        bytes,
+                        model: endpoint.spec.name.clone(),
+                        sampling: used,
+                    });
+                }
+                Err(e) => {
+                    if let Some(next) = next {
+                        warn!(
+                            "Model {} failed, falling back to {}: {:#}",
+                            endpoint.spec.name, next.spec.name, e
+                        );
+                        metrics::counter!(
+                            "meme_generator_model_fallbacks_total",
+                            1,
+                            "class" => params.class.as_str(),
+                            "model" => endpoint.spec.name.clone()
+                        );
+                    }
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.unwrap_or_else(|| anyhow::anyhow!("No models configured")))
+    }
+}
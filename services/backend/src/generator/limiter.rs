@@ -0,0 +1,253 @@
+// ===== UPSTREAM LIMITER =====
+// Providers like Hugging Face throttle per API token, not per pod, so scaling out only turns
+// into a flood of 429s unless every replica shares one budget. `UpstreamLimiter::run` wraps
+// each call to the provider: it first takes one of `UPSTREAM_MAX_CONCURRENCY` slots and the
+// next of the `UPSTREAM_MAX_RPS` start times from Redis, waiting until both are available
+// instead of failing. Slots are leases that are renewed while the call runs, so a replica
+// that dies mid-call only holds on to its slot until the lease runs out.
+
+use anyhow::{Context, Result};
+use redis::{aio::ConnectionManager, AsyncCommands, Script};
+use sha2::{Digest, Sha256};
+use std::{convert::Infallible, future::Future, time::Duration};
+use tracing::{debug, warn};
+use uuid::Uuid;
+
+/// How long a slot stays taken without being renewed
+const SLOT_LEASE: Duration = Duration::from_secs(30);
+
+/// How often a running call renews its slot
+const SLOT_RENEW_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Bounds of the backoff between attempts to take a slot while all of them are in use
+const SLOT_POLL_MIN: Duration = Duration::from_millis(50);
+const SLOT_POLL_MAX: Duration = Duration::from_secs(1);
+
+/// Takes a slot if fewer than the maximum are leased
+///
+/// KEYS[1] is a sorted set of slot holders scored by lease expiry.
+/// ARGV: holder ID, maximum number of holders, lease in ms.
+const ACQUIRE_SCRIPT: &str = r"
+local now = redis.call('TIME')
+local now_ms = tonumber(now[1]) * 1000 + math.floor(tonumber(now[2]) / 1000)
+redis.call('ZREMRANGEBYSCORE', KEYS[1], '-inf', now_ms)
+if redis.call('ZCARD', KEYS[1]) >= tonumber(ARGV[2]) then
+  return 0
+end
+redis.call('ZADD', KEYS[1], now_ms + tonumber(ARGV[3]), ARGV[1])
+redis.call('PEXPIRE', KEYS[1], ARGV[3])
+return 1
+";
+
+/// Extends the lease of a slot that is still held
+///
+/// KEYS[1] is the slot set, ARGV: holder ID, lease in ms.
+const RENEW_SCRIPT: &str = r"
+local now = redis.call('TIME')
+local now_ms = tonumber(now[1]) * 1000 + math.floor(tonumber(now[2]) / 1000)
+redis.call('ZADD', KEYS[1], 'XX', now_ms + tonumber(ARGV[2]), ARGV[1])
+redis.call('PEXPIRE', KEYS[1], ARGV[2])
+return 1
+";
+
+/// Reserves the next start time and returns how many ms to wait for it
+///
+/// KEYS[1] holds the earliest time the next call may start, ARGV[1] is the minimum interval
+/// between calls in ms.
+const RESERVE_SCRIPT: &str = r"
+local now = redis.call('TIME')
+local now_ms = tonumber(now[1]) * 1000 + math.floor(tonumber(now[2]) / 1000)
+local interval = tonumber(ARGV[1])
+local start = math.max(tonumber(redis.call('GET', KEYS[1])) or 0, now_ms)
+redis.call('SET', KEYS[1], start + interval, 'PX', start - now_ms + interval + 1000)
+return start - now_ms
+";
+
+/// Short, non-reversible identifier for an API token, used to name its Redis keys
+pub fn token_fingerprint(token: &str) -> String {
+    hex::encode(&Sha256::digest(token.trim().as_bytes())[..8])
+}
+
+/// Random duration between zero and `max`, to keep replicas from retrying in lockstep
+pub fn jitter(max: Duration) -> Duration {
+    let millis = max.as_millis().max(1);
+    Duration::from_millis((Uuid::new_v4().as_u128() % millis) as u64)
+}
+
+/// Cluster-wide limits on the calls made to one provider account
+pub struct UpstreamLimiter {
+    redis: ConnectionManager,
+    /// Key prefix identifying the provider and token, e.g. `huggingface:<fingerprint>`
+    scope: String,
+    max_concurrency: u32,
+    /// Minimum time between two calls, or None when the rate is unlimited
+    interval: Option<Duration>,
+    acquire: Script,
+    renew: Script,
+    reserve: Script,
+}
+
+impl UpstreamLimiter {
+    /// Creates a limiter; `max_rps` and `max_concurrency` of 0 disable the respective limit
+    pub fn new(
+        redis: ConnectionManager,
+        scope: String,
+        max_rps: f64,
+        max_concurrency: u32,
+    ) -> Self {
+        let interval = (max_rps > 0.0).then(|| Duration::from_secs_f64(1.0 / max_rps));
+        Self {
+            redis,
+            scope,
+            max_concurrency,
+            interval,
+            acquire: Script::new(ACQUIRE_SCRIPT),
+            renew: Script::new(RENEW_SCRIPT),
+            reserve: Script::new(RESERVE_SCRIPT),
+        }
+    }
+
+    /// Whether neither limit is configured
+    pub fn is_unlimited(&self) -> bool {
+        self.max_concurrency == 0 && self.interval.is_none()
+    }
+
+    /// Runs one call to `provider` once a slot and a start time are available
+    ///
+    /// The slot is held, and its lease renewed, until the call completes or is dropped.
+    pub async fn run<F: Future>(&self, provider: &'static str, call: F) -> F::Output {
+        if self.is_unlimited() {
+            return call.await;
+        }
+        let start = std::time::Instant::now();
+
+        // Fail open - losing Redis should not stop memes from being generated
+        let slot = match self.acquire_slot().await {
+            Ok(slot) => slot,
+            Err(e) => {
+                warn!("Calling {} without a concurrency slot: {:#}", provider, e);
+                metrics::counter!("meme_generator_upstream_limiter_errors_total", 1);
+                None
+            }
+        };
+        if let Err(e) = self.wait_turn().await {
+            warn!(
+                "Calling {} without a rate limit reservation: {:#}",
+                provider, e
+            );
+            metrics::counter!("meme_generator_upstream_limiter_errors_total", 1);
+        }
+
+        let waited = start.elapsed().as_secs_f64();
+        metrics::histogram!("meme_generator_upstream_wait_seconds", waited, "provider" => provider);
+        debug!("Waited {:.3}s for the {} limiter", waited, provider);
+
+        match slot {
+            Some(slot) => tokio::select! {
+                output = call => output,
+                never = self.keep_leased(&slot) => match never {},
+            },
+            None => call.await,
+        }
+    }
+
+    fn slots_key(&self) -> String {
+        format!("meme-upstream:{}:slots", self.scope)
+    }
+
+    fn rate_key(&self) -> String {
+        format!("meme-upstream:{}:next", self.scope)
+    }
+
+    /// Waits for a free slot; returns None when concurrency is unlimited
+    async fn acquire_slot(&self) -> Result<Option<Slot>> {
+        if self.max_concurrency == 0 {
+            return Ok(None);
+        }
+
+        let key = self.slots_key();
+        let id = Uuid::new_v4().to_string();
+        let mut redis = self.redis.clone();
+        let mut delay = SLOT_POLL_MIN;
+        loop {
+            let acquired: bool = self
+                .acquire
+                .key(&key)
+                .arg(&id)
+                .arg(self.max_concurrency)
+                .arg(SLOT_LEASE.as_millis() as u64)
+                .invoke_async(&mut redis)
+                .await
+                .context("Failed to acquire an upstream slot")?;
+            if acquired {
+                return Ok(Some(Slot { redis, key, id }));
+            }
+            tokio::time::sleep(delay + jitter(delay)).await;
+            delay = (delay * 2).min(SLOT_POLL_MAX);
+        }
+    }
+
+    /// Waits for the next start time allowed by the rate limit
+    async fn wait_turn(&self) -> Result<()> {
+        let Some(interval) = self.interval else {
+            return Ok(());
+        };
+        let mut redis = self.redis.clone();
+        let wait_ms: u64 = self
+            .reserve
+            .key(self.rate_key())
+            .arg(interval.as_millis().max(1) as u64)
+            .invoke_async(&mut redis)
+            .await
+            .context("Failed to reserve an upstream call")?;
+        if wait_ms > 0 {
+            tokio::time::sleep(Duration::from_millis(wait_ms)).await;
+        }
+        Ok(())
+    }
+
+    /// Renews a slot's lease until dropped
+    async fn keep_leased(&self, slot: &Slot) -> Infallible {
+        let mut redis = self.redis.clone();
+        let mut ticker = tokio::time::interval_at(
+            tokio::time::Instant::now() + SLOT_RENEW_INTERVAL,
+            SLOT_RENEW_INTERVAL,
+        );
+        loop {
+            ticker.tick().await;
+            let renewed: Result<i64, _> = self
+                .renew
+                .key(&slot.key)
+                .arg(&slot.id)
+                .arg(SLOT_LEASE.as_millis() as u64)
+                .invoke_async(&mut redis)
+                .await;
+            if let Err(e) = renewed {
+                warn!("Failed to renew upstream slot: {}", e);
+            }
+        }
+    }
+}
+
+/// A leased concurrency slot, given back when dropped
+struct Slot {
+    redis: ConnectionManager,
+    key: String,
+    id: String,
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        let mut redis = self.redis.clone();
+        let key = std::mem::take(&mut self.key);
+        let id = std::mem::take(&mut self.id);
+        tokio::spawn(async move {
+            if let Err(e) = redis.zrem::<_, _, ()>(&key, &id).await {
+                warn!(
+                    "Failed to release upstream slot, it expires with its lease: {}",
+                    e
+                );
+            }
+        });
+    }
+}
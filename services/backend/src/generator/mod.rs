@@ -0,0 +1,190 @@
+// ===== IMAGE GENERATION =====
+// Image providers live behind the `ImageGenerator` trait so that the NATS/Redis plumbing in
+// `main.rs` never needs to know which service actually produced the pixels. Each provider
+// gets its own submodule, and `build_generator` picks one at startup based on `Config`.
+// Remote providers share one upstream budget across replicas through `UpstreamLimiter`, and
+// retry failed calls behind a circuit breaker (see `resilience`).
+
+use anyhow::{bail, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use clap::ValueEnum;
+use redis::aio::ConnectionManager;
+use serde::{Deserialize, Serialize};
+use std::{sync::Arc, time::Duration};
+
+use crate::models::ModelRegistry;
+use crate::Config;
+
+mod huggingface;
+mod limiter;
+mod procedural;
+mod resilience;
+
+pub use huggingface::HuggingFaceGenerator;
+pub use limiter::UpstreamLimiter;
+pub use procedural::ProceduralGenerator;
+pub use resilience::CircuitOpen;
+
+/// Image generation providers that can be selected per deployment
+///
+/// The value is chosen with `--image-provider` / `IMAGE_PROVIDER`, so the same
+/// container image can be pointed at a different backend without a rebuild. The same names
+/// are used for the `provider` of models in the model registry.
+#[derive(ValueEnum, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ImageProvider {
+    /// Hugging Face inference API (FLUX / Stable Diffusion models)
+    #[value(name = "huggingface")]
+    HuggingFace,
+    /// Deterministic offline renderer for demos and CI (no token or network needed)
+    Procedural,
+}
+
+/// Kind of request, which decides the models a provider tries
+///
+/// The class only trades quality for latency; the image size is chosen separately.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ModelClass {
+    /// Regular requests, favouring image quality
+    Quality,
+    /// `fast_mode` requests, favouring latency
+    Fast,
+}
+
+impl ModelClass {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            ModelClass::Quality => "quality",
+            ModelClass::Fast => "fast",
+        }
+    }
+}
+
+/// Most denoising steps a client may ask for
+const MAX_INFERENCE_STEPS: u32 = 100;
+
+/// Highest guidance scale a client may ask for
+const MAX_GUIDANCE_SCALE: f32 = 30.0;
+
+/// Maximum number of characters accepted for a negative prompt
+const MAX_NEGATIVE_PROMPT_LENGTH: usize = 500;
+
+/// Sampling settings that, together with the prompt, size and model, pin down an image
+///
+/// Clients may set any of them. Providers report the values they actually used, including
+/// a seed they picked themselves, so that responses carry everything needed to reproduce
+/// an image. Unset values are left to the model's defaults.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct SamplingParams {
+    /// Kept to 32 bits, which every diffusion backend accepts and JavaScript clients can
+    /// represent exactly
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seed: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub num_inference_steps: Option<u32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub guidance_scale: Option<f32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub negative_prompt: Option<String>,
+}
+
+impl SamplingParams {
+    /// Checks values a client asked for
+    pub fn validate(&self) -> Result<()> {
+        if let Some(steps) = self.num_inference_steps {
+            if !(1..=MAX_INFERENCE_STEPS).contains(&steps) {
+                bail!(
+                    "num_inference_steps must be between 1 and {}",
+                    MAX_INFERENCE_STEPS
+                );
+            }
+        }
+        if let Some(scale) = self.guidance_scale {
+            if !(0.0..=MAX_GUIDANCE_SCALE).contains(&scale) {
+                bail!(
+                    "guidance_scale must be between 0 and {}",
+                    MAX_GUIDANCE_SCALE
+                );
+            }
+        }
+        if let Some(negative_prompt) = &self.negative_prompt {
+            if negative_prompt.chars().count() > MAX_NEGATIVE_PROMPT_LENGTH {
+                bail!(
+                    "Negative prompt is too long (maximum {} characters)",
+                    MAX_NEGATIVE_PROMPT_LENGTH
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Provider-agnostic parameters for a single generation
+///
+/// These are derived from the incoming `MemeRequest` by `process_request`, so that
+/// individual providers never have to interpret client-facing flags themselves.
+#[derive(Debug, Clone)]
+pub struct GenerationParams {
+    /// The client's prompt before the style wrapped it, for providers that show it as text
+    pub user_prompt: String,
+    /// Output width in pixels
+    pub width: u32,
+    /// Output height in pixels
+    pub height: u32,
+    /// Which of the provider's model chains to use
+    pub class: ModelClass,
+    /// Registry name of the model the client picked, used instead of the class's chain
+    pub model: Option<String>,
+    /// Sampling settings the client asked for
+    pub sampling: SamplingParams,
+}
+
+/// Raw image produced by a provider
+#[derive(Debug, Clone)]
+pub struct GeneratedImage {
+    /// Encoded image bytes exactly as returned by the provider
+    pub bytes: Bytes,
+    /// Identifier of the model that produced the image, reported in responses and metrics
+    pub model: String,
+    /// Sampling settings the image was generated with, as far as the provider supports them
+    pub sampling: SamplingParams,
+}
+
+/// Common interface implemented by every image generation backend
+///
+/// Implementations must be cheap to share between tasks: a single instance is
+/// created at startup and handed to every request through `AppState`.
+#[async_trait]
+pub trait ImageGenerator: Send + Sync {
+    /// Short provider name used in logs and metrics
+    fn name(&self) -> &'static str;
+
+    /// Generate an image for a fully formatted prompt
+    async fn generate(&self, prompt: &str, params: &GenerationParams) -> Result<GeneratedImage>;
+}
+
+/// Creates the image generator selected in the configuration
+///
+/// The Hugging Face limits are kept per API token, so deployments sharing a token share them.
+pub fn build_generator(
+    config: &Config,
+    models: Arc<ModelRegistry>,
+    redis: ConnectionManager,
+) -> Result<Arc<dyn ImageGenerator>> {
+    match config.image_provider {
+        ImageProvider::HuggingFace => {
+            let token = config.hf_api_token.as_deref().unwrap_or_default();
+            let limiter = UpstreamLimiter::new(
+                redis,
+                format!("huggingface:{}", limiter::token_fingerprint(token)),
+                config.upstream_max_rps,
+                config.upstream_max_concurrency,
+            );
+            Ok(Arc::new(HuggingFaceGenerator::new(config, models, limiter)?))
+        }
+        ImageProvider::Procedural => Ok(Arc::new(ProceduralGenerator::new(
+            Duration::from_millis(config.procedural_delay_ms),
+        ))),
+    }
+}
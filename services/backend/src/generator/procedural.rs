@@ -0,0 +1,226 @@
+// ===== PROCEDURAL PROVIDER =====
+// Renders an image locally without any network access, so the service can run on a laptop
+// or in CI without a Hugging Face token. Output is fully deterministic: the prompt,
+// dimensions and optional seed feed a small PRNG that picks the gradient, the shapes and
+// their colours, and the user's prompt (without the style's template text) is written into
+// a band across the middle of the image, leaving the top and bottom free for captions.
+
+use ab_glyph::PxScale;
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use image::{ImageOutputFormat, Rgba, RgbaImage};
+use std::{io::Cursor, time::Duration};
+
+use super::{GeneratedImage, GenerationParams, ImageGenerator, SamplingParams};
+use crate::text;
+
+/// Maximum number of prompt lines drawn into the caption band
+const MAX_TEXT_LINES: usize = 6;
+
+/// Image generator that draws seeded gradients and shapes instead of calling a model
+pub struct ProceduralGenerator {
+    /// Artificial latency added to every generation to mimic a remote provider
+    delay: Duration,
+}
+
+impl ProceduralGenerator {
+    /// Creates a generator that waits `delay` before returning each image
+    pub fn new(delay: Duration) -> Self {
+        Self { delay }
+    }
+}
+
+#[async_trait]
+impl ImageGenerator for ProceduralGenerator {
+    fn name(&self) -> &'static str {
+        "procedural"
+    }
+
+    async fn generate(&self, prompt: &str, params: &GenerationParams) -> Result<GeneratedImage> {
+        // Simulated provider latency keeps the autoscaling demos meaningful offline
+        if !self.delay.is_zero() {
+            tokio::time::sleep(self.delay).await;
+        }
+
+        // The styled prompt varies the drawing per style, but only the user's words are shown
+        let prompt = prompt.to_string();
+        let label = params.user_prompt.clone();
+        let (width, height) = (params.width, params.height);
+        let seed = params.sampling.seed;
+
+        // Rendering and PNG encoding are CPU-bound, so keep them off the async workers
+        let bytes = tokio::task::spawn_blocking(move || {
+            render_png(&prompt, &label, width, height, seed)
+        })
+        .await
+        .context("Procedural rendering task failed")??;
+
+        // The seed is the only sampling setting that affects the drawing
+        Ok(GeneratedImage {
+            bytes: Bytes::from(bytes),
+            model: "procedural".to_string(),
+            sampling: SamplingParams {
+                seed,
+                ..SamplingParams::default()
+            },
+        })
+    }
+}
+
+/// Renders the image for a prompt, with `label` written across it, and encodes it as PNG
+fn render_png(
+    prompt: &str,
+    label: &str,
+    width: u32,
+    height: u32,
+    seed: Option<u32>,
+) -> Result<Vec<u8>> {
+    // Without a seed the image only depends on the prompt and size, as it always has
+    let seed = seed.map_or(0, |seed| SplitMix64::new(seed.into()).next_u64());
+    let mut rng = SplitMix64::new(
+        fnv1a(prompt.as_bytes()) ^ ((width as u64) << 32 | height as u64) ^ seed,
+    );
+    let mut image = RgbaImage::new(width, height);
+
+    draw_gradient(&mut image, &mut rng);
+    draw_shapes(&mut image, &mut rng);
+    draw_prompt(&mut image, label);
+
+    let mut png = Cursor::new(Vec::new());
+    image
+        .write_to(&mut png, ImageOutputFormat::Png)
+        .context("Failed to encode procedural image as PNG")?;
+    Ok(png.into_inner())
+}
+
+/// Fills the image with a linear gradient between two seeded colours at a seeded angle
+fn draw_gradient(image: &mut RgbaImage, rng: &mut SplitMix64) {
+    let hue = rng.next_f32() * 360.0;
+    let from = hsv_to_rgba(hue, 0.65, 0.95, 255);
+    let to = hsv_to_rgba((hue + 90.0 + rng.next_f32() * 180.0) % 360.0, 0.75, 0.55, 255);
+
+    let angle = rng.next_f32() * std::f32::consts::TAU;
+    let (dx, dy) = (angle.cos(), angle.sin());
+    let (w, h) = (image.width() as f32, image.height() as f32);
+    // Project the corners onto the gradient axis so `t` spans the full 0..1 range
+    let extent = (w * dx).abs() + (h * dy).abs();
+    let origin = (if dx < 0.0 { w * dx } else { 0.0 }) + (if dy < 0.0 { h * dy } else { 0.0 });
+
+    for (x, y, pixel) in image.enumerate_pixels_mut() {
+        let t = ((x as f32 * dx + y as f32 * dy - origin) / extent).clamp(0.0, 1.0);
+        for channel in 0..3 {
+            pixel[channel] =
+                (from[channel] as f32 + (to[channel] as f32 - from[channel] as f32) * t) as u8;
+        }
+        pixel[3] = 255;
+    }
+}
+
+/// Scatters translucent circles and rectangles across the image
+fn draw_shapes(image: &mut RgbaImage, rng: &mut SplitMix64) {
+    let (w, h) = (image.width() as f32, image.height() as f32);
+    let count = 6 + rng.next_u64() % 7;
+
+    for _ in 0..count {
+        let color = hsv_to_rgba(rng.next_f32() * 360.0, 0.7, 0.9, 90 + (rng.next_u64() % 80) as u8);
+        let cx = rng.next_f32() * w;
+        let cy = rng.next_f32() * h;
+        let size = (0.05 + rng.next_f32() * 0.2) * w.min(h);
+        let is_circle = rng.next_u64() & 1 == 0;
+
+        let x0 = (cx - size).max(0.0) as u32;
+        let y0 = (cy - size).max(0.0) as u32;
+        let x1 = ((cx + size) as u32).min(image.width());
+        let y1 = ((cy + size) as u32).min(image.height());
+        for y in y0..y1 {
+            for x in x0..x1 {
+                let (fx, fy) = (x as f32 - cx, y as f32 - cy);
+                if !is_circle || fx * fx + fy * fy <= size * size {
+                    text::blend_pixel(image.get_pixel_mut(x, y), color, 1.0);
+                }
+            }
+        }
+    }
+}
+
+/// Writes the prompt into a dark band across the vertical centre of the image
+fn draw_prompt(image: &mut RgbaImage, prompt: &str) {
+    let font = text::default_font();
+    let (w, h) = (image.width() as f32, image.height() as f32);
+    let scale = PxScale::from((w / 28.0).max(12.0));
+    let margin = w / 32.0;
+    let line_height = text::line_height(font, scale);
+
+    let mut lines = text::wrap_text(font, scale, prompt, w - 2.0 * margin);
+    if lines.len() > MAX_TEXT_LINES {
+        lines.truncate(MAX_TEXT_LINES);
+        if let Some(last) = lines.last_mut() {
+            last.push('…');
+        }
+    }
+
+    let band_height = lines.len() as f32 * line_height + 2.0 * margin;
+    let band_top = ((h - band_height) / 2.0).max(0.0);
+    let shade = Rgba([0, 0, 0, 150]);
+    for y in band_top as u32..((band_top + band_height) as u32).min(image.height()) {
+        for x in 0..image.width() {
+            text::blend_pixel(image.get_pixel_mut(x, y), shade, 1.0);
+        }
+    }
+
+    for (i, line) in lines.iter().enumerate() {
+        let y = band_top + margin + i as f32 * line_height;
+        text::draw_text(image, font, scale, margin, y, line, Rgba([255, 255, 255, 255]));
+    }
+}
+
+/// Converts an HSV colour (hue in degrees) to RGBA
+fn hsv_to_rgba(hue: f32, saturation: f32, value: f32, alpha: u8) -> Rgba<u8> {
+    let c = value * saturation;
+    let x = c * (1.0 - ((hue / 60.0) % 2.0 - 1.0).abs());
+    let m = value - c;
+    let (r, g, b) = match (hue / 60.0) as u32 {
+        0 => (c, x, 0.0),
+        1 => (x, c, 0.0),
+        2 => (0.0, c, x),
+        3 => (0.0, x, c),
+        4 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+    Rgba([
+        ((r + m) * 255.0) as u8,
+        ((g + m) * 255.0) as u8,
+        ((b + m) * 255.0) as u8,
+        alpha,
+    ])
+}
+
+/// FNV-1a hash, used instead of `DefaultHasher` because its output is stable across builds
+fn fnv1a(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0000_0100_0000_01b3)
+    })
+}
+
+/// Tiny deterministic PRNG so identical prompts always render identical images
+struct SplitMix64(u64);
+
+impl SplitMix64 {
+    fn new(seed: u64) -> Self {
+        Self(seed)
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    /// Uniform float in `0.0..1.0`
+    fn next_f32(&mut self) -> f32 {
+        (self.next_u64() >> 40) as f32 / (1u64 << 24) as f32
+    }
+}
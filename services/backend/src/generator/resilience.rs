@@ -0,0 +1,433 @@
+// ===== RETRIES AND CIRCUIT BREAKERS =====
+// Calls to a remote provider fail for reasons that usually go away on their own: throttling
+// (429), overloaded or restarting servers (5xx), timeouts, and models that are still loading.
+// `RetryPolicy` retries those with exponential backoff and jitter, waiting as long as the
+// provider asks to via `Retry-After` or the Hugging Face `estimated_time` field (capped at
+// `UPSTREAM_RETRY_MAX_MS`, after which the provider is simply asked again). When an
+// endpoint keeps failing anyway, its `CircuitBreaker` opens and requests fail fast for a
+// cooldown instead of piling up behind a provider that is down; after the cooldown a single
+// trial call decides whether it closes again.
+
+use std::{
+    fmt,
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime},
+};
+use tracing::{info, warn};
+
+use super::limiter::jitter;
+use crate::Config;
+
+/// A failed call to a remote provider
+#[derive(Debug)]
+pub struct UpstreamError {
+    /// HTTP status, or None when no response was received (timeout, connection error)
+    pub status: Option<reqwest::StatusCode>,
+    /// How long the provider asked us to wait, from `Retry-After` or `estimated_time`
+    pub retry_after: Option<Duration>,
+    /// The model is still being loaded - the provider is up, just not ready yet
+    pub loading: bool,
+    pub message: String,
+}
+
+impl UpstreamError {
+    /// Whether the same call may succeed if repeated
+    pub fn is_retryable(&self) -> bool {
+        match self.status {
+            None => true,
+            Some(status) => {
+                status == reqwest::StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+            }
+        }
+    }
+
+    /// Whether the failure suggests the endpoint is down, as opposed to busy or misused
+    pub fn is_outage(&self) -> bool {
+        !self.loading && self.status.is_none_or(|status| status.is_server_error())
+    }
+}
+
+impl fmt::Display for UpstreamError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+impl std::error::Error for UpstreamError {}
+
+/// Parses a `Retry-After` header, given either in seconds or as an HTTP date
+pub fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let date = httpdate::parse_http_date(value).ok()?;
+    // A date in the past means the call may be repeated right away
+    Some(
+        date.duration_since(SystemTime::now())
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+/// Error returned without calling the provider while an endpoint's circuit is open
+#[derive(Debug)]
+pub struct CircuitOpen {
+    pub endpoint: String,
+    /// Time until the endpoint is tried again
+    pub retry_after: Duration,
+}
+
+impl fmt::Display for CircuitOpen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Image provider {} is unavailable, try again in {} seconds",
+            self.endpoint,
+            self.retry_after.as_secs().max(1)
+        )
+    }
+}
+
+impl std::error::Error for CircuitOpen {}
+
+/// How failed calls are retried
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// Retries after the first attempt
+    pub max_retries: u32,
+    /// Backoff before the first retry, doubled for every further one
+    pub base_delay: Duration,
+    /// Longest wait between attempts; a provider asking for more is retried after this long
+    pub max_delay: Duration,
+}
+
+impl RetryPolicy {
+    pub fn new(config: &Config) -> Self {
+        Self {
+            max_retries: config.upstream_max_retries,
+            base_delay: Duration::from_millis(config.upstream_retry_base_ms),
+            max_delay: Duration::from_millis(config.upstream_retry_max_ms),
+        }
+    }
+
+    /// Wait before retry number `retry` (1-based) after `error`, or None to give up
+    pub fn delay(&self, retry: u32, error: &UpstreamError) -> Option<Duration> {
+        if retry > self.max_retries || !error.is_retryable() {
+            return None;
+        }
+        match error.retry_after {
+            // Model loading estimates are often longer than max_delay; waiting that long and
+            // asking again beats giving up on a model that is about to be ready
+            Some(requested) => Some(requested.min(self.max_delay) + jitter(self.base_delay)),
+            None => {
+                // Exponential backoff with equal jitter: half fixed, half random
+                let backoff = self
+                    .base_delay
+                    .saturating_mul(1 << (retry - 1).min(16))
+                    .min(self.max_delay);
+                Some(backoff / 2 + jitter(backoff / 2))
+            }
+        }
+    }
+}
+
+/// State of a circuit breaker, exported as the `meme_generator_circuit_breaker_state` gauge
+#[derive(Debug, Clone, Copy)]
+enum BreakerState {
+    /// Calls go through; counts consecutive outage failures
+    Closed { failures: u32 },
+    /// Calls fail fast until the cooldown is over
+    Open { until: Instant },
+    /// A trial call is running; others fail fast until it finishes or its cooldown lapses
+    HalfOpen { since: Instant },
+}
+
+impl BreakerState {
+    fn gauge_value(self) -> f64 {
+        match self {
+            BreakerState::Closed { .. } => 0.0,
+            BreakerState::HalfOpen { .. } => 1.0,
+            BreakerState::Open { .. } => 2.0,
+        }
+    }
+}
+
+/// Circuit breaker for one model endpoint
+pub struct CircuitBreaker {
+    endpoint: String,
+    threshold: u32,
+    cooldown: Duration,
+    state: Mutex<BreakerState>,
+}
+
+impl CircuitBreaker {
+    /// Creates a closed breaker that opens after `threshold` consecutive outage failures
+    /// (0 never opens it) and stays open for `cooldown`
+    pub fn new(endpoint: String, threshold: u32, cooldown: Duration) -> Self {
+        let breaker = Self {
+            endpoint,
+            threshold,
+            cooldown,
+            state: Mutex::new(BreakerState::Closed { failures: 0 }),
+        };
+        breaker.export(BreakerState::Closed { failures: 0 });
+        breaker
+    }
+
+    /// Checks whether a call may be made now
+    pub fn check(&self) -> Result<(), CircuitOpen> {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let retry_after = match *state {
+            BreakerState::Closed { .. } => return Ok(()),
+            BreakerState::Open { until } if now >= until => Duration::ZERO,
+            BreakerState::Open { until } => until - now,
+            BreakerState::HalfOpen { since } if now >= since + self.cooldown => Duration::ZERO,
+            BreakerState::HalfOpen { since } => since + self.cooldown - now,
+        };
+        if retry_after.is_zero() {
+            info!(endpoint = %self.endpoint, "Circuit half-open, sending a trial request");
+            *state = BreakerState::HalfOpen { since: now };
+            self.export(*state);
+            return Ok(());
+        }
+        Err(CircuitOpen {
+            endpoint: self.endpoint.clone(),
+            retry_after,
+        })
+    }
+
+    pub fn record_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        if !matches!(*state, BreakerState::Closed { .. }) {
+            info!(endpoint = %self.endpoint, "Circuit closed, endpoint recovered");
+        }
+        *state = BreakerState::Closed { failures: 0 };
+        self.export(*state);
+    }
+
+    pub fn record_failure(&self, error: &UpstreamError) {
+        let mut state = self.state.lock().unwrap();
+        if !error.is_outage() {
+            // Throttling or a bad request still proves the endpoint is up
+            if matches!(*state, BreakerState::HalfOpen { .. }) {
+                *state = BreakerState::Closed { failures: 0 };
+                self.export(*state);
+            }
+            return;
+        }
+        let failures = match *state {
+            BreakerState::Closed { failures } => failures + 1,
+            // A failed trial reopens the circuit right away
+            BreakerState::HalfOpen { .. } | BreakerState::Open { .. } => self.threshold,
+        };
+        *state = if self.threshold > 0 && failures >= self.threshold {
+            warn!(
+                endpoint = %self.endpoint,
+                "Circuit opened after {} failures, failing fast for {:?}",
+                failures, self.cooldown
+            );
+            metrics::counter!("meme_generator_circuit_breaker_opened_total", 1, "endpoint" => self.endpoint.clone());
+            BreakerState::Open {
+                until: Instant::now() + self.cooldown,
+            }
+        } else {
+            BreakerState::Closed { failures }
+        };
+        self.export(*state);
+    }
+
+    fn export(&self, state: BreakerState) {
+        metrics::gauge!(
+            "meme_generator_circuit_breaker_state",
+            state.gauge_value(),
+            "endpoint" => self.endpoint.clone()
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use reqwest::StatusCode;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 3,
+            base_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(1),
+        }
+    }
+
+    fn error(status: Option<StatusCode>, retry_after: Option<Duration>) -> UpstreamError {
+        UpstreamError {
+            status,
+            retry_after,
+            loading: false,
+            message: "test".into(),
+        }
+    }
+
+    fn outage() -> UpstreamError {
+        error(Some(StatusCode::BAD_GATEWAY), None)
+    }
+
+    fn breaker(threshold: u32, cooldown: Duration) -> CircuitBreaker {
+        CircuitBreaker::new("test".into(), threshold, cooldown)
+    }
+
+    #[test]
+    fn backoff_grows_and_is_capped() {
+        let policy = policy();
+        let first = policy.delay(1, &outage()).unwrap();
+        assert!(first >= Duration::from_millis(50) && first <= Duration::from_millis(100));
+        let second = policy.delay(2, &outage()).unwrap();
+        assert!(second >= Duration::from_millis(100) && second <= Duration::from_millis(200));
+
+        let long = RetryPolicy {
+            max_retries: 10,
+            ..policy
+        };
+        let capped = long.delay(10, &outage()).unwrap();
+        assert!(capped >= Duration::from_millis(500) && capped <= Duration::from_secs(1));
+    }
+
+    #[test]
+    fn gives_up_after_max_retries() {
+        assert!(policy().delay(3, &outage()).is_some());
+        assert!(policy().delay(4, &outage()).is_none());
+    }
+
+    #[test]
+    fn client_errors_are_not_retried() {
+        let policy = policy();
+        assert!(policy
+            .delay(1, &error(Some(StatusCode::BAD_REQUEST), None))
+            .is_none());
+        assert!(policy
+            .delay(1, &error(Some(StatusCode::TOO_MANY_REQUESTS), None))
+            .is_some());
+        assert!(policy.delay(1, &error(None, None)).is_some());
+    }
+
+    #[test]
+    fn requested_wait_is_honoured() {
+        let requested = Duration::from_millis(700);
+        let delay = policy()
+            .delay(
+                1,
+                &error(Some(StatusCode::SERVICE_UNAVAILABLE), Some(requested)),
+            )
+            .unwrap();
+        assert!(delay >= requested && delay <= requested + Duration::from_millis(100));
+    }
+
+    #[test]
+    fn long_requested_wait_is_clamped_and_retried() {
+        let delay = policy()
+            .delay(
+                1,
+                &error(
+                    Some(StatusCode::SERVICE_UNAVAILABLE),
+                    Some(Duration::from_secs(120)),
+                ),
+            )
+            .unwrap();
+        assert!(delay >= Duration::from_secs(1) && delay <= Duration::from_millis(1100));
+    }
+
+    #[test]
+    fn retry_after_in_seconds() {
+        assert_eq!(parse_retry_after(" 30 "), Some(Duration::from_secs(30)));
+        assert_eq!(parse_retry_after("soon"), None);
+    }
+
+    #[test]
+    fn retry_after_as_http_date() {
+        let future = httpdate::fmt_http_date(SystemTime::now() + Duration::from_secs(60));
+        let wait = parse_retry_after(&future).unwrap();
+        assert!(wait > Duration::from_secs(55) && wait <= Duration::from_secs(60));
+
+        let past = httpdate::fmt_http_date(SystemTime::now() - Duration::from_secs(60));
+        assert_eq!(parse_retry_after(&past), Some(Duration::ZERO));
+    }
+
+    #[test]
+    fn breaker_opens_after_threshold_and_fails_fast() {
+        let breaker = breaker(3, Duration::from_secs(60));
+        for _ in 0..2 {
+            breaker.record_failure(&outage());
+            assert!(breaker.check().is_ok());
+        }
+        breaker.record_failure(&outage());
+        let open = breaker.check().unwrap_err();
+        assert!(open.retry_after > Duration::ZERO && open.retry_after <= Duration::from_secs(60));
+    }
+
+    #[test]
+    fn success_resets_the_failure_count() {
+        let breaker = breaker(3, Duration::from_secs(60));
+        breaker.record_failure(&outage());
+        breaker.record_failure(&outage());
+        breaker.record_success();
+        breaker.record_failure(&outage());
+        breaker.record_failure(&outage());
+        assert!(breaker.check().is_ok());
+    }
+
+    #[test]
+    fn busy_or_loading_endpoints_do_not_open_the_breaker() {
+        let breaker = breaker(1, Duration::from_secs(60));
+        breaker.record_failure(&error(Some(StatusCode::TOO_MANY_REQUESTS), None));
+        breaker.record_failure(&UpstreamError {
+            loading: true,
+            ..error(Some(StatusCode::SERVICE_UNAVAILABLE), None)
+        });
+        assert!(breaker.check().is_ok());
+    }
+
+    #[test]
+    fn zero_threshold_never_opens() {
+        let breaker = breaker(0, Duration::from_secs(60));
+        for _ in 0..10 {
+            breaker.record_failure(&outage());
+        }
+        assert!(breaker.check().is_ok());
+    }
+
+    #[test]
+    fn half_open_trial_closes_or_reopens() {
+        let breaker = breaker(1, Duration::ZERO);
+        breaker.record_failure(&outage());
+        // The cooldown is over, so the next call is the trial
+        assert!(breaker.check().is_ok());
+        assert!(matches!(
+            *breaker.state.lock().unwrap(),
+            BreakerState::HalfOpen { .. }
+        ));
+
+        breaker.record_failure(&outage());
+        assert!(matches!(
+            *breaker.state.lock().unwrap(),
+            BreakerState::Open { .. }
+        ));
+
+        assert!(breaker.check().is_ok());
+        breaker.record_success();
+        assert!(matches!(
+            *breaker.state.lock().unwrap(),
+            BreakerState::Closed { failures: 0 }
+        ));
+    }
+
+    #[test]
+    fn half_open_allows_only_one_trial() {
+        let breaker = breaker(1, Duration::from_secs(60));
+        breaker.record_failure(&outage());
+        *breaker.state.lock().unwrap() = BreakerState::Open {
+            until: Instant::now(),
+        };
+        assert!(breaker.check().is_ok());
+        assert!(breaker.check().is_err());
+    }
+}
@@ -0,0 +1,302 @@
+// ===== RATE LIMITS AND QUOTAS =====
+// Every request is checked against per-user, per-IP and global limits before any work is
+// done: a token bucket that allows `*_RATE_LIMIT` requests per minute (in bursts of up to
+// that many), and a quota of `*_DAILY_QUOTA` requests per UTC day. Requests without a user
+// identity share one anonymous bucket, so leaving the identity out does not escape the
+// limits, and the global bucket caps the total when clients keep inventing new identities.
+// The counters live in Redis and are checked and updated by a single Lua script, so the
+// limits hold across all replicas and a request is only counted when every limit allows
+// it. Requests are counted once: a marker keyed by the message's stream position lets
+// redeliveries through without using up the client's allowance.
+//
+// Identities come from the request payload, which clients control. `client_ip` is only
+// trusted from gateways that prove themselves with the `GATEWAY_TOKEN`; requests carrying
+// it without the token are rejected, so clients cannot spread their requests over made-up
+// addresses.
+
+use anyhow::{Context, Result};
+use redis::{aio::ConnectionManager, Script};
+use std::fmt;
+use std::time::Duration;
+
+use crate::{fairness, Config, ErrorCode, MemeRequest};
+
+/// Header a trusted gateway sets to the `GATEWAY_TOKEN` to vouch for a request's `client_ip`
+pub const GATEWAY_TOKEN_HEADER: &str = "Meme-Gateway-Token";
+
+/// Prefix of every limit key
+///
+/// The `{...}` hash tag puts all of them in the same Redis Cluster slot, since the check
+/// script touches the counted marker and several unrelated counters in one EVAL and would
+/// otherwise fail with CROSSSLOT. The price is that all limit keys live on one shard.
+const KEY_PREFIX: &str = "{meme-limits}";
+
+/// How long a request is remembered as already counted
+const COUNTED_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Period the rate limits are expressed in
+const RATE_PERIOD: Duration = Duration::from_secs(60);
+
+/// Checks every limit for a request and consumes one unit of each if all of them allow it
+///
+/// KEYS[1] marks the request as counted, KEYS[2..] hold one token bucket or daily counter per
+/// limit. ARGV holds the kind, size and period (ms) of each limit, then the marker TTL (ms).
+/// Returns the 1-based index of the limit that blocks the longest and the wait in ms, or
+/// `{0, 0}` when the request is allowed. Redis' clock is used so replicas agree on the time.
+/// Every key carries the `KEY_PREFIX` hash tag, as a script may only touch keys of one slot.
+const CHECK_SCRIPT: &str = r"
+local now = redis.call('TIME')
+local now_ms = tonumber(now[1]) * 1000 + math.floor(tonumber(now[2]) / 1000)
+if redis.call('EXISTS', KEYS[1]) == 1 then
+  return {0, 0}
+end
+
+local day_ms = 86400000
+local until_midnight = day_ms - now_ms % day_ms
+local exceeded, wait = 0, 0
+local tokens = {}
+for i = 2, #KEYS do
+  local kind = ARGV[i * 3 - 5]
+  local size = tonumber(ARGV[i * 3 - 4])
+  local period = tonumber(ARGV[i * 3 - 3])
+  local retry = 0
+  if kind == 'rate' then
+    local bucket = redis.call('HMGET', KEYS[i], 'tokens', 'updated')
+    local available = tonumber(bucket[1]) or size
+    local updated = tonumber(bucket[2]) or now_ms
+    available = math.min(size, available + math.max(0, now_ms - updated) * size / period)
+    tokens[i] = available
+    if available < 1 then
+      retry = math.ceil((1 - available) * period / size)
+    end
+  elseif (tonumber(redis.call('GET', KEYS[i])) or 0) >= size then
+    retry = until_midnight
+  end
+  if retry > wait then
+    exceeded, wait = i - 1, retry
+  end
+end
+if exceeded > 0 then
+  return {exceeded, wait}
+end
+
+for i = 2, #KEYS do
+  if ARGV[i * 3 - 5] == 'rate' then
+    redis.call('HSET', KEYS[i], 'tokens', tostring(tokens[i] - 1), 'updated', now_ms)
+    redis.call('PEXPIRE', KEYS[i], ARGV[i * 3 - 3])
+  elseif redis.call('INCR', KEYS[i]) == 1 then
+    redis.call('PEXPIRE', KEYS[i], until_midnight)
+  end
+end
+redis.call('SET', KEYS[1], 1, 'PX', ARGV[#ARGV])
+return {0, 0}
+";
+
+/// Kind of limit a request ran into
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LimitKind {
+    /// Too many requests per minute
+    Rate,
+    /// Too many requests today
+    Quota,
+}
+
+impl LimitKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            LimitKind::Rate => "rate",
+            LimitKind::Quota => "quota",
+        }
+    }
+}
+
+/// One limit that applies to a request
+struct Limit {
+    kind: LimitKind,
+    /// Who the limit is counted for: `user`, `anonymous`, `ip` or `global`
+    scope: &'static str,
+    key: String,
+    size: u32,
+    period: Duration,
+}
+
+/// Error returned for requests rejected by a rate limit or quota
+#[derive(Debug)]
+pub struct LimitExceeded {
+    pub kind: LimitKind,
+    pub scope: &'static str,
+    /// Seconds until the request would be allowed
+    pub retry_after: u64,
+}
+
+impl LimitExceeded {
+    /// Error code reported to the client
+    pub fn code(&self) -> ErrorCode {
+        match self.kind {
+            LimitKind::Rate => ErrorCode::RateLimited,
+            LimitKind::Quota => ErrorCode::QuotaExceeded,
+        }
+    }
+}
+
+impl fmt::Display for LimitExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let who = match self.scope {
+            "ip" => "your IP address",
+            "anonymous" => "anonymous clients",
+            "global" => "all clients",
+            _ => "your account",
+        };
+        match self.kind {
+            LimitKind::Rate => write!(f, "Too many requests from {}", who)?,
+            LimitKind::Quota => write!(f, "Daily meme quota reached for {}", who)?,
+        }
+        write!(f, ", try again in {} seconds", self.retry_after)
+    }
+}
+
+impl std::error::Error for LimitExceeded {}
+
+/// Enforces the per-user, per-IP and global limits in Redis
+pub struct RateLimiter {
+    redis: ConnectionManager,
+    script: Script,
+    user_rate_limit: u32,
+    user_daily_quota: u32,
+    anonymous_rate_limit: u32,
+    anonymous_daily_quota: u32,
+    ip_rate_limit: u32,
+    ip_daily_quota: u32,
+    global_rate_limit: u32,
+    global_daily_quota: u32,
+    gateway_token: Option<String>,
+}
+
+impl RateLimiter {
+    pub fn new(redis: ConnectionManager, config: &Config) -> Self {
+        Self {
+            redis,
+            script: Script::new(CHECK_SCRIPT),
+            user_rate_limit: config.user_rate_limit,
+            user_daily_quota: config.user_daily_quota,
+            anonymous_rate_limit: config.anonymous_rate_limit,
+            anonymous_daily_quota: config.anonymous_daily_quota,
+            ip_rate_limit: config.ip_rate_limit,
+            ip_daily_quota: config.ip_daily_quota,
+            global_rate_limit: config.global_rate_limit,
+            global_daily_quota: config.global_daily_quota,
+            gateway_token: config.gateway_token.clone(),
+        }
+    }
+
+    /// Rejects a `client_ip` that was not vouched for by a trusted gateway
+    pub fn verify_client_ip(&self, request: &MemeRequest) -> Result<()> {
+        if request.client_ip.is_none() {
+            return Ok(());
+        }
+        match (&self.gateway_token, &request.gateway_token) {
+            (Some(expected), Some(token)) if expected == token => Ok(()),
+            _ => anyhow::bail!("client_ip may only be set by a trusted gateway"),
+        }
+    }
+
+    /// Counts the request against its limits, or returns the limit it exceeds
+    ///
+    /// Requests are counted per user (`user_id`, falling back to `session_id` and `reply_to`),
+    /// or in the shared anonymous bucket when they carry none of these, per `client_ip` and
+    /// globally.
+    pub async fn check(&self, request: &MemeRequest) -> Result<Option<LimitExceeded>> {
+        let limits = self.limits(request);
+        if limits.is_empty() {
+            return Ok(None);
+        }
+        let delivery = request
+            .delivery
+            .as_ref()
+            .context("Request has no stream position to count it by")?;
+
+        let mut invocation = self.script.prepare_invoke();
+        invocation.key(format!("{}:counted:{}", KEY_PREFIX, delivery));
+        for limit in &limits {
+            invocation
+                .key(&limit.key)
+                .arg(limit.kind.as_str())
+                .arg(limit.size)
+                .arg(limit.period.as_millis() as u64);
+        }
+        invocation.arg(COUNTED_TTL.as_millis() as u64);
+
+        let mut redis = self.redis.clone();
+        let (exceeded, wait_ms): (usize, u64) = invocation
+            .invoke_async(&mut redis)
+            .await
+            .context("Failed to check rate limits")?;
+
+        Ok(exceeded
+            .checked_sub(1)
+            .and_then(|index| limits.get(index))
+            .map(|limit| LimitExceeded {
+                kind: limit.kind,
+                scope: limit.scope,
+                retry_after: wait_ms.div_ceil(1000),
+            }))
+    }
+
+    /// Limits that apply to a request; limits configured as 0 are disabled
+    fn limits(&self, request: &MemeRequest) -> Vec<Limit> {
+        let user = fairness::client_key(request);
+        let anonymous = user.is_none().then(|| "anonymous".to_string());
+        let scopes = [
+            (
+                "user",
+                user,
+                self.user_rate_limit,
+                self.user_daily_quota,
+            ),
+            (
+                "anonymous",
+                anonymous,
+                self.anonymous_rate_limit,
+                self.anonymous_daily_quota,
+            ),
+            (
+                "ip",
+                request.client_ip.as_ref().map(|ip| format!("ip:{}", ip)),
+                self.ip_rate_limit,
+                self.ip_daily_quota,
+            ),
+            (
+                "global",
+                Some("global".to_string()),
+                self.global_rate_limit,
+                self.global_daily_quota,
+            ),
+        ];
+
+        let mut limits = Vec::new();
+        for (scope, id, rate_limit, daily_quota) in scopes {
+            let Some(id) = id else {
+                continue;
+            };
+            if rate_limit > 0 {
+                limits.push(Limit {
+                    kind: LimitKind::Rate,
+                    scope,
+                    key: format!("{}:rate:{}", KEY_PREFIX, id),
+                    size: rate_limit,
+                    period: RATE_PERIOD,
+                });
+            }
+            if daily_quota > 0 {
+                limits.push(Limit {
+                    kind: LimitKind::Quota,
+                    scope,
+                    key: format!("{}:quota:{}", KEY_PREFIX, id),
+                    size: daily_quota,
+                    period: Duration::ZERO,
+                });
+            }
+        }
+        limits
+    }
+}
@@ -5,24 +5,58 @@
 // - base64: For encoding/decoding images for transmission
 // - clap: For command-line argument and environment variable configuration
 // - futures: For asynchronous stream handling
+// - async_trait: For the pluggable image generation providers
 // - metrics: For application telemetry and monitoring
 // - redis: For caching generated images to improve performance
 // - tracing: For structured logging with request context tracking
 
+mod caption;
+mod cancellation;
+mod dlq;
+mod fairness;
+mod generator;
+mod limits;
+mod models;
+mod provisioning;
+mod scheduler;
+mod status;
+mod storage;
+mod styles;
+mod templates;
+mod text;
+
 use anyhow::{Context, Result};
-use async_nats::{self, jetstream};
+use async_nats::{self, jetstream, jetstream::AckKind};
 use base64::{engine::general_purpose::STANDARD, Engine};
-use clap::Parser;
+use bytes::Bytes;
+use clap::{Parser, Subcommand};
 use futures::stream::StreamExt;
 use metrics_exporter_prometheus::PrometheusBuilder;
 use redis::{aio::ConnectionManager, AsyncCommands};
 use serde::{Deserialize, Serialize};
-use std::{sync::Arc, time::Duration};
-use tokio::sync::Mutex;
+use sha2::{Digest, Sha256};
+use std::{path::PathBuf, sync::Arc, time::Duration};
+use tokio::sync::{watch, Mutex, Semaphore};
 use tracing::{debug, error, info, instrument, warn};
 use tracing_subscriber::{filter::EnvFilter, layer::SubscriberExt, util::SubscriberInitExt};
 use uuid::Uuid;
 
+use caption::CaptionRenderer;
+use cancellation::InFlight;
+use dlq::{DeadLetterQueue, DlqCommand};
+use generator::{
+    CircuitOpen, GeneratedImage, GenerationParams, ImageGenerator, ImageProvider, ModelClass,
+    SamplingParams,
+};
+use limits::{LimitExceeded, RateLimiter};
+use models::{AspectRatio, ImageSize, ModelRegistry};
+use provisioning::ReconcileMode;
+use scheduler::{Lane, LaneSpec, Pulled, Scheduler};
+use status::{JobState, JobStatus, StatusTracker};
+use storage::{ImageInfo, ImageStorage, ImageStore, StoredImage};
+use styles::{Style, StyleLibrary};
+use templates::TemplateLibrary;
+
 // ===== CONFIGURATION =====
 // This struct defines our service configuration with sensible defaults for Kubernetes.
 // We use clap's derive feature to automatically parse environment variables, making the
@@ -55,10 +89,24 @@ struct Config {
     #[clap(long, env = "NATS_REQUEST_SUBJECT", default_value = "meme.request")]
     request_subject: String,
 
+    /// Request lanes - Comma-separated `name:weight` pairs; each lane is consumed from
+    /// `<request_subject>.<name>` and gets processing slots in proportion to its weight
+    #[clap(long, env = "REQUEST_LANES", value_delimiter = ',', default_value = "high:3,normal:1")]
+    request_lanes: Vec<LaneSpec>,
+
+    /// Default lane - Lane that also receives requests published to the plain request subject
+    #[clap(long, env = "DEFAULT_LANE", default_value = "normal")]
+    default_lane: String,
+
     /// NATS response subject - Channel for sending generated memes back to clients
     #[clap(long, env = "NATS_RESPONSE_SUBJECT", default_value = "meme.response")]
     response_subject: String,
 
+    /// Private responses - Publish to `<response_subject>.<request_id>` when a request has no
+    /// `reply_to`, instead of broadcasting it to every client on the shared response subject
+    #[clap(long, env = "PRIVATE_RESPONSES", default_value = "false")]
+    private_responses: bool,
+
     /// Redis URL - Used for caching generated images to improve performance
     #[clap(
         long,
@@ -67,10 +115,10 @@ struct Config {
     )]
     redis_url: String,
 
-    /// Hugging Face API Token - Required for accessing the image generation API
+    /// Hugging Face API Token - Required when the huggingface image provider is used
     /// This is deliberately not given a default value to force explicit configuration
     #[clap(long, env = "HF_API_TOKEN")]
-    hf_api_token: String,
+    hf_api_token: Option<String>,
 
     /// Hugging Face API URL - Model endpoint for image generation
     /// Default is stable-diffusion-v1-5, but code will use FLUX model for fast_mode
@@ -81,14 +129,286 @@ struct Config {
     )]
     hf_api_url: String,
 
+    /// Hugging Face fast model URL - Endpoint used for fast_mode requests
+    #[clap(
+        long,
+        env = "HF_FAST_API_URL",
+        default_value = "https://router.huggingface.co/hf-inference/models/black-forest-labs/FLUX.1-schnell"
+    )]
+    hf_fast_api_url: String,
+
+    /// Quality model chain - Comma-separated model IDs or endpoint URLs tried in order for
+    /// regular requests when the previous one fails; defaults to HF_API_URL alone
+    #[clap(long, env = "HF_QUALITY_MODELS", value_delimiter = ',')]
+    hf_quality_models: Vec<String>,
+
+    /// Fast model chain - Models tried in order for fast_mode requests; defaults to
+    /// HF_FAST_API_URL alone
+    #[clap(long, env = "HF_FAST_MODELS", value_delimiter = ',')]
+    hf_fast_models: Vec<String>,
+
+    /// Small model chain - Deprecated and ignored: small images use the quality or fast chain.
+    /// Still accepted so existing deployments keep starting
+    #[clap(long, env = "HF_SMALL_MODELS", value_delimiter = ',')]
+    hf_small_models: Vec<String>,
+
+    /// Model registry - TOML file listing the available models and the chain for each class
+    /// When set, it replaces the HF_*_API_URL and HF_*_MODELS settings above
+    #[clap(long, env = "MODELS_FILE")]
+    models_file: Option<PathBuf>,
+
+    /// Image provider - Selects which backend generates the images
+    #[clap(long, env = "IMAGE_PROVIDER", value_enum, default_value = "huggingface")]
+    image_provider: ImageProvider,
+
+    /// Upstream rate limit - Calls per second to the image provider across all replicas
+    /// Shared by every replica using the same API token (0 disables)
+    #[clap(long, env = "UPSTREAM_MAX_RPS", default_value = "2")]
+    upstream_max_rps: f64,
+
+    /// Upstream concurrency - Provider calls in flight at once across all replicas (0 disables)
+    #[clap(long, env = "UPSTREAM_MAX_CONCURRENCY", default_value = "8")]
+    upstream_max_concurrency: u32,
+
+    /// Upstream retries - Retries after a failed provider call (429, 5xx, timeouts)
+    #[clap(long, env = "UPSTREAM_MAX_RETRIES", default_value = "3")]
+    upstream_max_retries: u32,
+
+    /// Upstream retry base delay - Backoff before the first retry in milliseconds, doubled
+    /// for every further retry
+    #[clap(long, env = "UPSTREAM_RETRY_BASE_MS", default_value = "500")]
+    upstream_retry_base_ms: u64,
+
+    /// Upstream retry max delay - Longest wait between attempts in milliseconds; a provider
+    /// asking to wait longer (Retry-After, estimated_time) is retried after this long
+    #[clap(long, env = "UPSTREAM_RETRY_MAX_MS", default_value = "30000")]
+    upstream_retry_max_ms: u64,
+
+    /// Circuit breaker threshold - Consecutive failures that open a model endpoint's circuit
+    /// (0 disables the breaker)
+    #[clap(long, env = "CIRCUIT_BREAKER_THRESHOLD", default_value = "5")]
+    circuit_breaker_threshold: u32,
+
+    /// Circuit breaker cooldown - Seconds an open circuit fails fast before a trial request
+    #[clap(long, env = "CIRCUIT_BREAKER_COOLDOWN", default_value = "30")]
+    circuit_breaker_cooldown: u64,
+
+    /// Procedural provider delay - Simulated generation latency in milliseconds
+    /// Lets offline autoscaling demos build up queue depth like a real provider would
+    #[clap(long, env = "PROCEDURAL_DELAY_MS", default_value = "0")]
+    procedural_delay_ms: u64,
+
+    /// Caption font - Path to a TrueType/OpenType font used for top/bottom captions
+    /// When unset, the bold Noto Sans face compiled into the binary is used
+    #[clap(long, env = "CAPTION_FONT")]
+    caption_font: Option<PathBuf>,
+
+    /// Templates directory - Metadata and images for classic template memes
+    /// Each `<id>.json` file describes one template; a missing directory disables templates
+    #[clap(long, env = "TEMPLATES_DIR", default_value = "templates")]
+    templates_dir: PathBuf,
+
+    /// Fallback template - Template used when the image provider fails
+    /// Lets users still get a meme while the AI provider is down
+    #[clap(long, env = "FALLBACK_TEMPLATE")]
+    fallback_template: Option<String>,
+
+    /// Styles file - TOML file with the prompt templates requests choose with `style`
+    /// Defaults to the bundled styles.toml
+    #[clap(long, env = "STYLES_FILE")]
+    styles_file: Option<PathBuf>,
+
+    /// Image storage - How images are delivered: inline (base64 in the response), filesystem, s3 or nats
+    /// Storage modes publish a key and URL instead of the image, keeping messages small
+    #[clap(long, env = "IMAGE_STORAGE", value_enum, default_value = "inline")]
+    image_storage: ImageStorage,
+
+    /// Storage directory - Where the filesystem store writes images
+    #[clap(long, env = "STORAGE_DIR", default_value = "images")]
+    storage_dir: PathBuf,
+
+    /// Storage public URL - Base URL clients fetch stored images from, e.g. a CDN
+    /// For s3 storage, presigned URLs are generated when this is unset
+    #[clap(long, env = "STORAGE_PUBLIC_URL")]
+    storage_public_url: Option<String>,
+
+    /// S3 bucket - Bucket the s3 store writes images to
+    #[clap(long, env = "S3_BUCKET")]
+    s3_bucket: Option<String>,
+
+    /// S3 endpoint - Custom endpoint for S3-compatible services such as MinIO
+    #[clap(long, env = "S3_ENDPOINT")]
+    s3_endpoint: Option<String>,
+
+    /// S3 region - Region of the bucket (any value works for MinIO)
+    #[clap(long, env = "S3_REGION", default_value = "us-east-1")]
+    s3_region: String,
+
+    /// S3 URL expiry in seconds - Lifetime of presigned image URLs
+    #[clap(long, env = "S3_URL_EXPIRY_SECS", default_value = "3600")]
+    s3_url_expiry_secs: u64,
+
+    /// Object store bucket - JetStream Object Store bucket the nats store writes images to
+    #[clap(long, env = "OBJECT_STORE_BUCKET", default_value = "meme-images")]
+    object_store_bucket: String,
+
+    /// Object store max age in seconds - How long images are kept in the bucket (0 = forever)
+    /// Should be longer than CACHE_TTL so cached references never point at expired objects
+    #[clap(long, env = "OBJECT_STORE_MAX_AGE_SECS", default_value = "86400")]
+    object_store_max_age_secs: u64,
+
     /// Redis cache TTL in seconds - How long to keep generated images cached
     /// Default of 1 hour balances storage efficiency with avoiding regeneration
     #[clap(long, env = "CACHE_TTL", default_value = "3600")]
     cache_ttl: u64,
 
+    /// Result TTL in seconds - How long delivered responses are kept per request message
+    /// A redelivered request republishes its stored response instead of generating again
+    /// Only responses referencing a stored image are kept; inline responses are not
+    #[clap(long, env = "RESULT_TTL", default_value = "86400")]
+    result_ttl: u64,
+
+    /// Max concurrency - Requests a single replica processes at the same time
+    /// The replica stops pulling from the stream while all slots are busy
+    #[clap(long, env = "MAX_CONCURRENCY", default_value = "8")]
+    max_concurrency: usize,
+
+    /// Max ack pending - Unacknowledged messages each lane's consumer allows across all replicas
+    /// Should be at least MAX_CONCURRENCY + FAIR_QUEUE_DEPTH times the maximum number of replicas
+    #[clap(long, env = "MAX_ACK_PENDING", default_value = "1000")]
+    max_ack_pending: i64,
+
+    /// Fair queue depth - Requests each replica keeps waiting beyond its free slots, so free
+    /// slots can go to users in turn (0 pulls only what can start immediately)
+    /// Waiting requests are hidden from KEDA's lag, so keep this below its lagThreshold
+    #[clap(long, env = "FAIR_QUEUE_DEPTH", default_value = "4")]
+    fair_queue_depth: usize,
+
+    /// Ack wait in seconds - How long JetStream waits for an ack before redelivering a message
+    #[clap(long, env = "ACK_WAIT", default_value = "60")]
+    ack_wait: u64,
+
+    /// Progress interval in seconds - How often in-progress acks are sent while a request runs
+    /// Must be well below ACK_WAIT so long generations are never redelivered mid-flight
+    #[clap(long, env = "PROGRESS_INTERVAL", default_value = "15")]
+    progress_interval: u64,
+
+    /// Shutdown grace period in seconds - How long in-flight requests may finish after SIGTERM
+    /// Requests still running afterwards are returned to the stream for another replica.
+    /// Keep this below the pod's terminationGracePeriodSeconds
+    #[clap(long, env = "SHUTDOWN_GRACE_PERIOD", default_value = "20")]
+    shutdown_grace_period: u64,
+
+    /// NATS cancel subject - Clients publish a request ID here to cancel that request
+    #[clap(long, env = "NATS_CANCEL_SUBJECT", default_value = "meme.cancel")]
+    cancel_subject: String,
+
+    /// Status bucket - JetStream KV bucket holding the status of every request
+    #[clap(long, env = "STATUS_BUCKET", default_value = "meme-status")]
+    status_bucket: String,
+
+    /// Status TTL in seconds - How long status records are kept
+    #[clap(long, env = "STATUS_TTL", default_value = "86400")]
+    status_ttl: u64,
+
+    /// NATS status subject - Request-reply subject for looking up a request's status by ID
+    #[clap(long, env = "NATS_STATUS_SUBJECT", default_value = "meme.status")]
+    status_subject: String,
+
+    /// Reconcile mode - What to do when an existing stream or consumer differs from this
+    /// configuration in a way that needs a recreate: warn (keep the live setting) or fail
+    #[clap(long, env = "RECONCILE_MODE", value_enum, default_value = "warn")]
+    reconcile_mode: ReconcileMode,
+
+    /// Dead-letter stream - Requests that exhausted their deliveries are copied here
+    #[clap(long, env = "DLQ_STREAM", default_value = "MEMES_DLQ")]
+    dlq_stream: String,
+
+    /// Dead-letter subject - Subject of the dead-letter stream
+    #[clap(long, env = "NATS_DLQ_SUBJECT", default_value = "meme.dlq")]
+    dlq_subject: String,
+
+    /// Dead-letter max age in seconds - How long dead-lettered requests are kept
+    #[clap(long, env = "DLQ_MAX_AGE", default_value = "604800")]
+    dlq_max_age: u64,
+
+    /// Dead-letter advisory stream - Captures max delivery advisories so requests that run
+    /// out of deliveries while no replica is running are still dead-lettered
+    #[clap(long, env = "DLQ_ADVISORY_STREAM", default_value = "MEMES_DLQ_ADVISORIES")]
+    dlq_advisory_stream: String,
+
+    /// User rate limit - Requests per minute allowed for each user or session (0 disables)
+    /// All limits are off by default; deployments switch on the ones they need
+    #[clap(long, env = "USER_RATE_LIMIT", default_value = "0")]
+    user_rate_limit: u32,
+
+    /// User daily quota - Requests per UTC day allowed for each user or session (0 disables)
+    #[clap(long, env = "USER_DAILY_QUOTA", default_value = "0")]
+    user_daily_quota: u32,
+
+    /// Anonymous rate limit - Requests per minute shared by all requests without a user,
+    /// session or reply token (0 disables)
+    #[clap(long, env = "ANONYMOUS_RATE_LIMIT", default_value = "0")]
+    anonymous_rate_limit: u32,
+
+    /// Anonymous daily quota - Requests per UTC day shared by all requests without a user,
+    /// session or reply token (0 disables)
+    #[clap(long, env = "ANONYMOUS_DAILY_QUOTA", default_value = "0")]
+    anonymous_daily_quota: u32,
+
+    /// IP rate limit - Requests per minute allowed for each client IP (0 disables)
+    #[clap(long, env = "IP_RATE_LIMIT", default_value = "0")]
+    ip_rate_limit: u32,
+
+    /// IP daily quota - Requests per UTC day allowed for each client IP (0 disables)
+    #[clap(long, env = "IP_DAILY_QUOTA", default_value = "0")]
+    ip_daily_quota: u32,
+
+    /// Global rate limit - Requests per minute allowed across all clients (0 disables)
+    /// Caps the total when clients keep changing their session ID
+    #[clap(long, env = "GLOBAL_RATE_LIMIT", default_value = "0")]
+    global_rate_limit: u32,
+
+    /// Global daily quota - Requests per UTC day allowed across all clients (0 disables)
+    #[clap(long, env = "GLOBAL_DAILY_QUOTA", default_value = "0")]
+    global_daily_quota: u32,
+
+    /// Gateway token - Secret that trusted gateways send in the Meme-Gateway-Token header
+    /// Requests carrying client_ip without it are rejected
+    #[clap(long, env = "GATEWAY_TOKEN")]
+    gateway_token: Option<String>,
+
     /// Metrics listen address - Port for exposing Prometheus metrics
     #[clap(long, env = "METRICS_ADDR", default_value = "0.0.0.0:9090")]
     metrics_addr: String,
+
+    /// Maintenance command to run instead of the service
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+impl Config {
+    /// Subject requests for `lane` are published to
+    fn lane_subject(&self, lane: &str) -> String {
+        format!("{}.{}", self.request_subject, lane)
+    }
+
+    /// Durable consumer for `lane`; the default lane keeps the original consumer name
+    fn lane_consumer(&self, lane: &str) -> String {
+        if lane == self.default_lane {
+            self.nats_consumer.clone()
+        } else {
+            format!("{}-{}", self.nats_consumer, lane)
+        }
+    }
+}
+
+/// Maintenance commands; without one the service runs as usual
+#[derive(Subcommand, Debug, Clone)]
+enum Command {
+    /// Inspect and replay dead-lettered requests
+    #[clap(subcommand)]
+    Dlq(DlqCommand),
 }
 
 // ===== MESSAGE TYPES =====
@@ -100,7 +420,24 @@ struct Config {
 ///
 /// This type includes optional parameters for controlling the generation process:
 /// - fast_mode: Uses a faster but potentially lower quality model
-/// - small_image: Generates a smaller 512x512 image instead of 1024x1024
+/// - small_image: Generates a smaller 512x512 image instead of 1024x1024, or halves the
+///   aspect ratio preset
+/// - width/height: Exact image size in pixels
+/// - aspect_ratio: Size preset such as "16:9", used instead of width/height
+/// - seed/num_inference_steps/guidance_scale/negative_prompt: Sampling settings passed to
+///   providers that support them; responses echo the values used so images can be reproduced
+/// - style: Name of the prompt template the prompt is wrapped in, e.g. "pixel-art"
+/// - top_text/bottom_text: Captions drawn onto the image by the service
+/// - template/text_boxes: Compose a classic template meme instead of generating an image
+/// - reply_to: Client token; responses go to `<response_subject>.<reply_to>` instead of
+///   being broadcast to every client
+/// - user_id/session_id: Who sent the request; queued requests are shared out fairly
+///   between users instead of being processed strictly in arrival order
+/// - client_ip: End user's address, set by a gateway publishing on their behalf; used
+///   together with the user for rate limits and daily quotas. Only accepted with the
+///   gateway token header, since anything else in the payload is up to the client
+/// - model: Name of a selectable model from the model registry, used instead of the
+///   request class's model chain
 ///
 /// Default values ensure backward compatibility if clients don't specify options.
 /// The ID is auto-generated if not provided for tracing through the system.
@@ -108,13 +445,156 @@ struct Config {
 struct MemeRequest {
     #[serde(default = "generate_uuid")]
     id: String,
+    #[serde(default)]
     prompt: String,
     #[serde(default = "default_fast_mode")]
     fast_mode: bool,
     #[serde(default = "default_small_image")]
     small_image: bool,
+    #[serde(default)]
+    width: Option<u32>,
+    #[serde(default)]
+    height: Option<u32>,
+    #[serde(default)]
+    aspect_ratio: Option<String>,
+    #[serde(flatten)]
+    sampling: SamplingParams,
+    #[serde(default)]
+    style: Option<String>,
+    #[serde(default)]
+    top_text: Option<String>,
+    #[serde(default)]
+    bottom_text: Option<String>,
+    #[serde(default)]
+    template: Option<String>,
+    #[serde(default)]
+    text_boxes: Vec<String>,
+    #[serde(default)]
+    reply_to: Option<String>,
+    #[serde(default)]
+    user_id: Option<String>,
+    #[serde(default)]
+    session_id: Option<String>,
+    #[serde(default)]
+    client_ip: Option<String>,
+    #[serde(default)]
+    model: Option<String>,
+    // Set from the message metadata, never from the payload
+    #[serde(skip)]
+    delivery: Option<Delivery>,
+    #[serde(skip)]
+    gateway_token: Option<String>,
+}
+
+/// Position of a request's message in the request stream
+///
+/// Unlike the client-supplied request ID it stays the same across redeliveries and cannot
+/// be reused by another client, so stored responses and response deduplication use it.
+#[derive(Debug, Clone)]
+struct Delivery {
+    stream: String,
+    sequence: u64,
 }
 
+impl Delivery {
+    /// Reads the stream position from a pulled message's metadata
+    fn of(message: &jetstream::Message) -> Option<Self> {
+        let info = message.info().ok()?;
+        Some(Self {
+            stream: info.stream.to_string(),
+            sequence: info.stream_sequence,
+        })
+    }
+}
+
+impl std::fmt::Display for Delivery {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.stream, self.sequence)
+    }
+}
+
+impl MemeRequest {
+    /// Subject that responses for this request are published on
+    ///
+    /// A valid `reply_to` token (or the request ID, when private responses are enabled)
+    /// is appended to the response subject so only the requesting client receives it.
+    /// Requests without one keep using the shared subject for backward compatibility.
+    fn response_subject(&self, config: &Config) -> String {
+        let token = match &self.reply_to {
+            Some(reply_to) => Some(reply_to.as_str()),
+            None if config.private_responses => Some(self.id.as_str()),
+            None => None,
+        };
+        match token.filter(|token| is_valid_reply_token(token)) {
+            Some(token) => format!("{}.{}", config.response_subject, token),
+            None => config.response_subject.clone(),
+        }
+    }
+
+    /// Size of the image to generate
+    ///
+    /// Explicit `width` and `height` win, then the `aspect_ratio` preset; otherwise the
+    /// image is square. Presets and the square default are halved for `small_image`.
+    fn image_size(&self) -> Result<ImageSize> {
+        match (self.width, self.height, &self.aspect_ratio) {
+            (Some(width), Some(height), None) => ImageSize::checked(width, height),
+            (None, None, Some(ratio)) => Ok(ratio.parse::<AspectRatio>()?.size(self.small_image)),
+            (None, None, None) => Ok(AspectRatio::Square.size(self.small_image)),
+            (Some(_), Some(_), Some(_)) => Err(anyhow::anyhow!(
+                "Give either width and height or aspect_ratio, not both"
+            )),
+            _ => Err(anyhow::anyhow!("width and height must be given together")),
+        }
+    }
+
+    /// Model chain the request is generated with, independently of its size
+    fn model_class(&self) -> ModelClass {
+        if self.fast_mode {
+            ModelClass::Fast
+        } else {
+            ModelClass::Quality
+        }
+    }
+
+    /// Texts to place in a template's boxes, in order
+    ///
+    /// Explicit `text_boxes` win; otherwise the top/bottom captions fill the first two
+    /// boxes, and a request without any text uses its prompt as the first box.
+    fn template_texts(&self) -> Vec<String> {
+        if !self.text_boxes.is_empty() {
+            self.text_boxes.clone()
+        } else if self.top_text.is_some() || self.bottom_text.is_some() {
+            vec![
+                self.top_text.clone().unwrap_or_default(),
+                self.bottom_text.clone().unwrap_or_default(),
+            ]
+        } else {
+            vec![self.prompt.clone()]
+        }
+    }
+}
+
+/// Checks that a reply token is a single, literal NATS subject token
+///
+/// Wildcards, separators and whitespace are rejected so that clients can only
+/// receive on their own subject below the response subject. `error` is reserved
+/// for the shared error subject.
+fn is_valid_reply_token(token: &str) -> bool {
+    !token.is_empty()
+        && token.len() <= 128
+        && token != "error"
+        && token
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// How long interrupted requests get to nak their messages after the grace period
+const INTERRUPT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Maximum number of characters accepted for a single caption
+/// Longer captions would shrink to an unreadable font size anyway
+const MAX_CAPTION_LENGTH: usize = 200;
+
 // Helper functions for default values in MemeRequest
 // Using functions rather than const values allows for dynamic generation
 
@@ -138,36 +618,108 @@ fn default_small_image() -> bool {
 
 /// Response containing a generated meme image
 ///
-/// The image_data field contains the base64-encoded image to avoid
-/// binary transmission issues and enable direct embedding in web pages.
-#[derive(Debug, Serialize, Deserialize)]
+/// In inline storage mode the image_data field contains the base64-encoded image to
+/// avoid binary transmission issues and enable direct embedding in web pages. With an
+/// image store configured, image_key and image_url reference the stored object instead.
+/// The image metadata (content type, dimensions and size) is always included, as are the
+/// model and sampling settings that generated the image, unless it was composed from a
+/// template.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct MemeResponse {
     request_id: String,
-    image_data: String, // Base64 encoded image
+    #[serde(skip_serializing_if = "Option::is_none")]
+    image_data: Option<String>, // Base64 encoded image (inline mode only)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    image_key: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    image_url: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    image_bucket: Option<String>, // Object store bucket holding image_key (nats storage)
+    #[serde(flatten)]
+    image_info: ImageInfo,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    model: Option<String>,
+    #[serde(flatten)]
+    sampling: SamplingParams,
     prompt: String,
     timestamp: u64,
 }
 
+/// A generated or cached image, either held in memory or already written to the image store
+enum ImageRef {
+    Inline(Bytes),
+    Stored(StoredImage),
+}
+
+/// Redis cache entry for a generated base image
+///
+/// Inline mode caches the base64 image, storage modes cache a reference to the stored object.
+#[derive(Debug, Serialize, Deserialize)]
+struct CachedImage {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    image_data: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    stored: Option<StoredImage>,
+    /// Model that generated the image
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    model: Option<String>,
+    /// Sampling settings the image was generated with
+    #[serde(default)]
+    sampling: SamplingParams,
+}
+
+impl CachedImage {
+    fn new(image: &ImageRef, model: Option<String>, sampling: SamplingParams) -> Self {
+        let (image_data, stored) = match image {
+            ImageRef::Inline(bytes) => (Some(STANDARD.encode(bytes)), None),
+            ImageRef::Stored(stored) => (None, Some(stored.clone())),
+        };
+        Self {
+            image_data,
+            stored,
+            model,
+            sampling,
+        }
+    }
+
+    /// The cached image, its model and sampling settings, or None for entries that cannot be used
+    fn into_image(self) -> Option<(ImageRef, Option<String>, SamplingParams)> {
+        let image = match (self.image_data, self.stored) {
+            (Some(image_data), _) => ImageRef::Inline(Bytes::from(STANDARD.decode(image_data).ok()?)),
+            (None, Some(stored)) => ImageRef::Stored(stored),
+            (None, None) => return None,
+        };
+        Some((image, self.model, self.sampling))
+    }
+}
+
 /// Error response for failed meme generation
 ///
 /// Sent when image generation fails for any reason.
-/// The error string provides details about what went wrong.
+/// The error string provides details about what went wrong, the code lets clients tell
+/// rejected requests apart, and retry_after says how many seconds to wait before retrying.
 #[derive(Debug, Serialize, Deserialize)]
 struct MemeError {
     request_id: String,
     error: String,
+    code: ErrorCode,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    retry_after: Option<u64>,
     timestamp: u64,
 }
 
-/// Request format for the Hugging Face API
-///
-/// Structured according to the Hugging Face inference API requirements.
-/// The inputs field contains the prompt text, while parameters can specify
-/// additional generation options like image dimensions.
-#[derive(Debug, Serialize)]
-struct HuggingFaceRequest {
-    inputs: String,
-    parameters: Option<serde_json::Value>,
+/// Machine-readable reason a request failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ErrorCode {
+    /// Validation or generation failed
+    Failed,
+    /// The user or IP sent too many requests in the last minute
+    RateLimited,
+    /// The user or IP used up its daily quota
+    QuotaExceeded,
+    /// The image provider is down and requests fail fast until it recovers
+    Unavailable,
 }
 
 // ===== APPLICATION STATE =====
@@ -193,9 +745,34 @@ struct AppState {
     /// Stored here to avoid passing it around to every function
     config: Config,
 
-    /// HTTP client for Hugging Face API requests
-    /// Pre-configured with timeout and authorization headers
-    http_client: reqwest::Client,
+    /// Image generation provider selected in the configuration
+    /// Shared behind an Arc so spawned tasks can use it without holding the lock
+    generator: Arc<dyn ImageGenerator>,
+
+    /// Caption renderer for top/bottom meme text
+    /// Holds the parsed font so it is only loaded once at startup
+    captions: Arc<CaptionRenderer>,
+
+    /// Classic meme templates loaded from the templates directory at startup
+    templates: Arc<TemplateLibrary>,
+
+    /// Image store responses reference, or None to embed images inline
+    store: Option<Arc<dyn ImageStore>>,
+
+    /// Job status records for request lookups
+    status: Arc<StatusTracker>,
+
+    /// Requests this replica is processing, so they can be cancelled
+    in_flight: Arc<InFlight>,
+
+    /// Per-user and per-IP rate limits and quotas, shared by all replicas through Redis
+    limiter: Arc<RateLimiter>,
+
+    /// Models loaded at startup, used to check the model a request asks for
+    models: Arc<ModelRegistry>,
+
+    /// Prompt templates requests choose with their `style` field
+    styles: Arc<StyleLibrary>,
 }
 
 // ===== APPLICATION ENTRY POINT =====
@@ -210,7 +787,7 @@ struct AppState {
 /// 3. Configure metrics for observability
 /// 4. Connect to message broker (NATS)
 /// 5. Connect to cache (Redis)
-/// 6. Set up API client (Hugging Face)
+/// 6. Set up the image generation provider
 /// 7. Start processing message queue
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -224,6 +801,11 @@ async fn main() -> Result<()> {
         .with(EnvFilter::from_default_env().add_directive("meme_generator=info".parse()?))
         .init();
 
+    // Run a maintenance command instead of the service if one was given
+    if let Some(Command::Dlq(command)) = config.command.clone() {
+        return dlq::run(&config, command).await;
+    }
+
     info!("Starting Meme Generator Service");
 
     // Set up metrics - Using Prometheus format for compatibility with monitoring tools
@@ -250,62 +832,117 @@ async fn main() -> Result<()> {
     });
     info!("Metrics server started on {}", config.metrics_addr);
 
+    // Check the lanes before connecting to anything
+    scheduler::validate_lanes(&config.request_lanes, &config.default_lane)?;
+
+    // Load the model registry - A bad MODELS_FILE fails at startup rather than per request
+    let models = Arc::new(ModelRegistry::load(&config)?);
+    match &config.models_file {
+        Some(path) => info!("Loaded {} models from {}", models.len(), path.display()),
+        None => info!("Using {} models from the HF_* settings", models.len()),
+    }
+    let styles = Arc::new(StyleLibrary::load(config.styles_file.as_deref())?);
+    info!(
+        "Loaded {} prompt styles, default '{}'",
+        styles.len(),
+        styles.default_name()
+    );
+
     // Connect to NATS - Our message broker for request/response handling
     // We use NATS for its simplicity, performance, and reliability
     info!("Connecting to NATS at {}", config.nats_url);
     let nats = async_nats::connect(&config.nats_url).await?;
     info!("NATS connection established successfully");
-    let js = jetstream::new(nats);
+    let js = jetstream::new(nats.clone());
     info!("Setting up JetStream with stream: {} and subject: {}", config.nats_stream, config.request_subject);
 
     // Ensure stream exists - Creating it if needed
     // The stream configuration uses:
     // - File storage for durability across restarts
     // - WorkQueue retention to process each message once
+    // - One subject per lane, plus the plain request subject for the default lane
+    let mut stream_subjects = vec![config.request_subject.clone()];
+    stream_subjects.extend(config.request_lanes.iter().map(|lane| config.lane_subject(&lane.name)));
     let stream_config = jetstream::stream::Config {
         name: config.nats_stream.clone(),
-        subjects: vec![config.request_subject.clone()], // Changed to exact subject
+        subjects: stream_subjects,
         storage: jetstream::stream::StorageType::File,
         retention: jetstream::stream::RetentionPolicy::WorkQueue,
         ..Default::default()
     };
 
-    // Get or create stream - This approach ensures the service is self-provisioning
+    // Get, create or reconcile the stream - This approach ensures the service is self-provisioning
     // and doesn't require external setup beyond infrastructure deployment
-    let mut stream = match js.get_stream(&config.nats_stream).await {
-        Ok(stream) => {
-            info!("Found existing stream: {}", config.nats_stream);
-            stream
-        },
-        Err(_) => {
-            info!("Creating new stream: {} for subject: {}", config.nats_stream, config.request_subject);
-            js.create_stream(stream_config).await?
-        }
-    };
+    let stream = provisioning::ensure_stream(&js, stream_config, config.reconcile_mode).await?;
     info!("Connected to NATS stream {}", config.nats_stream);
 
+    // Set up job status tracking - Records live in a KV bucket next to the stream
+    let status = Arc::new(
+        StatusTracker::new(&js, &config.status_bucket, Duration::from_secs(config.status_ttl))
+            .await?,
+    );
+    let status_server = status.clone();
+    let status_subject = config.status_subject.clone();
+    let status_client = nats.clone();
+    let flush_client = nats.clone();
+    tokio::spawn(async move {
+        if let Err(e) = status_server.serve(status_client, status_subject).await {
+            error!("Status request handler stopped: {:#}", e);
+        }
+    });
+
     // Connect to Redis - Used for caching generated images
     // This improves response times and reduces API costs for repeated requests
     info!("Connecting to Redis at {}", config.redis_url);
     let redis_client = redis::Client::open(config.redis_url.clone())?;
     let redis = ConnectionManager::new(redis_client).await?;
     info!("Successfully connected to Redis");
-
-    // Create HTTP client for Hugging Face API - Pre-configured with authentication
-    // We use a single client for connection pooling and efficient resource usage
-    let mut headers = reqwest::header::HeaderMap::new();
-    headers.insert(
-        "Authorization",
-        format!("Bearer {}", config.hf_api_token)
-            .parse()
-            .context("Failed to parse authorization header")?,
+    let limiter = Arc::new(RateLimiter::new(redis.clone(), &config));
+    info!(
+        "Limits per user: {}/min, {}/day; anonymous: {}/min, {}/day; per IP: {}/min, {}/day; \
+        global: {}/min, {}/day (0 = unlimited)",
+        config.user_rate_limit,
+        config.user_daily_quota,
+        config.anonymous_rate_limit,
+        config.anonymous_daily_quota,
+        config.ip_rate_limit,
+        config.ip_daily_quota,
+        config.global_rate_limit,
+        config.global_daily_quota
     );
 
-    // Configure with a long timeout since image generation can take time
-    let http_client = reqwest::Client::builder()
-        .timeout(Duration::from_secs(300))
-        .default_headers(headers)
-        .build()?;
+    // Create the image generator - The provider is chosen per deployment via config
+    let generator = generator::build_generator(&config, models.clone(), redis.clone())?;
+    info!("Using image provider: {}", generator.name());
+    if config.image_provider == ImageProvider::HuggingFace {
+        info!(
+            "Upstream limits shared by all replicas: {} calls/s, {} concurrent (0 = unlimited)",
+            config.upstream_max_rps, config.upstream_max_concurrency
+        );
+    }
+
+    // Load the caption font up front so a bad CAPTION_FONT path fails at startup
+    let captions = Arc::new(CaptionRenderer::new(config.caption_font.as_deref())?);
+
+    // Load template memes - Images are decoded once here and reused for every request
+    let templates = Arc::new(TemplateLibrary::load(&config.templates_dir)?);
+    info!("Loaded {} meme templates from {}", templates.len(), config.templates_dir.display());
+    if let Some(fallback) = &config.fallback_template {
+        if templates.get(fallback).is_none() {
+            warn!("Fallback template '{}' is not available", fallback);
+        }
+    }
+
+    // Create the image store - Inline mode keeps sending base64 image data
+    let store = storage::build_store(&config, &js).await?;
+    match &store {
+        Some(store) => info!("Using image storage: {}", store.name()),
+        None => info!("Using inline image delivery"),
+    }
+
+    // Open the dead-letter stream - Requests that exhaust their deliveries end up here
+    let dead_letters = Arc::new(DeadLetterQueue::new(&js, &config).await?);
+    let advisories = dlq::advisory_consumer(&js, &config).await?;
 
     // Initialize application state - Shared between all request handlers
     // We use Arc<Mutex<>> for thread-safe access from multiple async tasks
@@ -313,37 +950,87 @@ async fn main() -> Result<()> {
         redis,
         js,
         config: config.clone(),
-        http_client,
+        generator,
+        captions,
+        templates,
+        store,
+        status,
+        in_flight: Arc::new(InFlight::default()),
+        limiter,
+        models,
+        styles,
     }));
 
-    // Set up consumer - Our subscription to the message queue
+    // Dead-letter requests that run out of deliveries - One replica handles each advisory
+    let dlq_state = state.clone();
+    let wake_client = nats.clone();
+    let dlq_requests = stream.clone();
+    tokio::spawn(async move {
+        if let Err(e) = dlq::process_advisories(dlq_state, advisories, dlq_requests, dead_letters).await {
+            error!("Dead-letter handler stopped: {:#}", e);
+        }
+    });
+
+    // Listen for cancellations - Every replica subscribes, the one running the request stops it
+    let cancel_state = state.clone();
+    tokio::spawn(async move {
+        if let Err(e) = process_cancellations(cancel_state, nats).await {
+            error!("Cancellation handler stopped: {:#}", e);
+        }
+    });
+
+    // Set up consumers - One subscription to the message queue per lane
     // The configuration ensures:
     // - Durable subscription (remembers position across restarts)
     // - Explicit acknowledgment (ensures processing completion)
     // - Limited retries (prevents infinite processing of bad messages)
-    info!("Setting up NATS consumer");
-    let consumer_config = jetstream::consumer::pull::Config {
-        durable_name: Some(config.nats_consumer.clone()),
-        deliver_policy: jetstream::consumer::DeliverPolicy::All,
-        ack_policy: jetstream::consumer::AckPolicy::Explicit,
-        filter_subjects: vec![config.request_subject.clone()], // Changed to exact subject
-        max_deliver: 3,
-        ack_wait: Duration::from_secs(60),
-        ..Default::default()
-    };
+    info!("Setting up NATS consumers");
+    let mut lanes = Vec::new();
+    for lane in &config.request_lanes {
+        let mut filter_subjects = vec![config.lane_subject(&lane.name)];
+        if lane.name == config.default_lane {
+            filter_subjects.push(config.request_subject.clone());
+        }
+        let consumer_config = jetstream::consumer::pull::Config {
+            durable_name: Some(config.lane_consumer(&lane.name)),
+            deliver_policy: jetstream::consumer::DeliverPolicy::All,
+            ack_policy: jetstream::consumer::AckPolicy::Explicit,
+            filter_subjects: filter_subjects.clone(),
+            max_deliver: 3,
+            ack_wait: Duration::from_secs(config.ack_wait),
+            max_ack_pending: config.max_ack_pending,
+            ..Default::default()
+        };
 
-    // Get or create consumer - Self-provisioning approach like with streams
-    let consumer = match stream.get_consumer(&config.nats_consumer).await {
-        Ok(consumer) => consumer,
-        Err(_) => stream.create_consumer(consumer_config).await?,
-    };
+        // Get, create or reconcile the consumer - Self-provisioning approach like with streams
+        // Settings changed here are applied to the existing consumer on the next start
+        let consumer =
+            provisioning::ensure_consumer(&stream, consumer_config, config.reconcile_mode).await?;
+        info!(
+            "Lane {} (weight {}) consuming {:?}",
+            lane.name, lane.weight, filter_subjects
+        );
+        lanes.push(Lane::new(lane, consumer));
+    }
 
-    info!("NATS consumer ready");
-    info!("Creating consumer for subject: {}", config.request_subject);
+    // Wake-up subscriptions - Let an idle replica pull as soon as a request is published
+    let wake = vec![
+        wake_client.subscribe(config.request_subject.clone()).await?,
+        wake_client.subscribe(format!("{}.*", config.request_subject)).await?,
+    ];
+    let scheduler = Scheduler::new(lanes, wake);
+    info!("NATS consumers ready");
 
     // Start processing messages - This will run until the service is terminated
     // All request handling happens inside this function
-    process_messages(state, consumer).await?;
+    process_messages(state, scheduler, shutdown_signal()).await?;
+
+    // Make sure every response, status update and nak has reached the server before exiting
+    flush_client
+        .flush()
+        .await
+        .context("Failed to flush NATS connection")?;
+    info!("Shutdown complete");
 
     Ok(())
 }
@@ -354,144 +1041,417 @@ async fn main() -> Result<()> {
 /// This function is the core of our service and implements the consumer pattern:
 /// 1. Continuously pulls messages from the NATS JetStream queue
 /// 2. Deserializes JSON messages into strongly-typed MemeRequest objects
-/// 3. Processes each request in a separate async task for concurrent handling
+/// 3. Processes each request in a separate async task, bounded by `MAX_CONCURRENCY`
 /// 4. Tracks metrics for monitoring and reliability analysis
 /// 5. Provides error handling and recovery at multiple levels
 async fn process_messages(
     state: Arc<Mutex<AppState>>,
-    consumer: async_nats::jetstream::consumer::Consumer<jetstream::consumer::pull::Config>,
+    mut scheduler: Scheduler,
+    shutdown: impl std::future::Future<Output = ()>,
 ) -> Result<()> {
-    // Get a stream of messages from the NATS consumer
-    // This is a potentially infinite stream that will continue until the service stops
-    let mut messages = consumer.messages().await?;
+    // Processing slots - Each in-flight request holds one permit until it is acked
+    let max_concurrency = state.lock().await.config.max_concurrency.max(1);
+    let semaphore = Arc::new(Semaphore::new(max_concurrency));
+
+    // In-progress acks - Keep JetStream from redelivering requests that are still running
+    let progress_interval = {
+        let config = &state.lock().await.config;
+        if config.progress_interval >= config.ack_wait {
+            warn!(
+                "PROGRESS_INTERVAL ({}s) is not below ACK_WAIT ({}s), long requests may be redelivered",
+                config.progress_interval, config.ack_wait
+            );
+        }
+        Duration::from_secs(config.progress_interval.max(1))
+    };
 
-    info!("Started processing messages");
+    // Interrupt signal for tasks still running when the shutdown grace period ends
+    let (interrupt_tx, interrupt_rx) = watch::channel(false);
+    tokio::pin!(shutdown);
+
+    info!("Started processing messages (max {} concurrent requests)", max_concurrency);
     
+    // Requests kept waiting beyond the free slots, so they can be handed out fairly
+    let fair_queue_depth = state.lock().await.config.fair_queue_depth;
+    let mut keep_alive =
+        tokio::time::interval_at(tokio::time::Instant::now() + progress_interval, progress_interval);
+
     let mut message_count = 0;
     info!("Waiting for messages on subject: {}", state.lock().await.config.request_subject);
 
     // Main message processing loop - runs indefinitely until the service is stopped
-    // Using a while-let pattern with async iterator is idiomatic for stream processing
-    while let Some(message) = messages.next().await {
-        message_count += 1;
-        info!("Received message #{}", message_count);
-        debug!("Message #{}", message_count);
-        // Handle NATS message retrieval errors gracefully
-        // This prevents network issues from crashing the entire service
-        let message = match message {
-            Ok(msg) => msg,
-            Err(e) => {
-                error!("Error receiving message: {}", e);
-                continue; // Skip to the next message
+    // Each iteration waits for free processing slots and pulls at most that many messages,
+    // so a saturated replica stops pulling and the backlog stays in the stream where KEDA
+    // can see it, instead of piling up inside the pod as in-flight tasks (apart from the
+    // few kept in the fair queue). The scheduler splits the free slots across the lanes by
+    // weight and across users within each lane
+    'pull: loop {
+        let first_permit = loop {
+            tokio::select! {
+                permit = semaphore.clone().acquire_owned() => break permit?,
+                _ = keep_alive.tick() => scheduler.keep_alive().await,
+                _ = &mut shutdown => break 'pull,
             }
         };
+        let mut permits = vec![first_permit];
+        while let Ok(permit) = semaphore.clone().try_acquire_owned() {
+            permits.push(permit);
+        }
 
-        // Parse the message payload into a strongly-typed MemeRequest
-        // We acknowledge malformed messages to remove them from the queue
-        // to prevent infinite redelivery of unparseable messages
-        let request: MemeRequest = match serde_json::from_slice(&message.payload) {
-            Ok(req) => req,
-            Err(e) => {
-                error!("Failed to parse message: {}", e);
-                if let Err(e) = message.ack().await {
-                    error!("Failed to ack message: {}", e);
-                }
-                continue; // Skip to the next message
+        // Top up the queue to the free slots plus the fair queue depth
+        let wanted = (permits.len() + fair_queue_depth).saturating_sub(scheduler.queued());
+        if wanted > 0 {
+            scheduler.fill(wanted).await;
+        }
+        if scheduler.queued() == 0 {
+            // Every lane is empty - release the slots and wait for new requests
+            drop(permits);
+            tokio::select! {
+                _ = scheduler.wait() => continue 'pull,
+                _ = &mut shutdown => break 'pull,
             }
-        };
+        }
 
-        // Log each request with structured metadata for request tracing
-        // This enables correlation of logs across the entire request lifecycle
-        // and makes debugging easier in a distributed system
-        info!(
-            request_id = %request.id,
-            prompt = %request.prompt,
-            "Received meme generation request"
-        );
+        while !permits.is_empty() {
+            let Some(pulled) = scheduler.next() else {
+                break;
+            };
+            let Pulled { lane, message, request } = pulled;
+            message_count += 1;
+            info!("Received message #{} on lane {}", message_count, lane);
+            metrics::counter!("meme_generator_lane_requests_total", 1, "lane" => lane);
+
+            // Log each request with structured metadata for request tracing
+            // This enables correlation of logs across the entire request lifecycle
+            // and makes debugging easier in a distributed system
+            info!(
+                request_id = %request.id,
+                prompt = %request.prompt,
+                user = %fairness::fairness_key(&request),
+                "Received meme generation request"
+            );
 
-        let req_id = request.id.clone();
-        let state_clone = state.clone();
-
-        // Process each message in a separate task to enable concurrent processing
-        // This is critical for throughput as it allows multiple requests to be
-        // processed simultaneously, especially important since image generation
-        // can take several seconds per request
-        tokio::spawn(async move {
-            // Track total request count for capacity planning and monitoring
-            metrics::counter!("meme_generator_requests_total", 1);
-            let start = std::time::Instant::now();
-
-            // Call the main request processing function that handles image generation
-            let result = process_request(&state_clone, request.clone()).await;
-
-            // Record processing time for performance monitoring and SLA tracking
-            // This helps identify slow requests and performance degradation
-            let duration = start.elapsed().as_secs_f64();
-            metrics::histogram!("meme_generator_processing_duration_seconds", duration);
-
-            match result {
-                Ok(_) => {
-                    // Success case - log and track for monitoring
-                    info!(request_id = %req_id, "Successfully processed request");
-                    metrics::counter!("meme_generator_success_total", 1);
+            let req_id = request.id.clone();
+            let state_clone = state.clone();
+            let permit = permits.pop();
+            let attempts = message.info().ok().map(|info| info.delivered as u64);
+            let mut interrupt = interrupt_rx.clone();
+
+            // Process each message in a separate task to enable concurrent processing
+            // This is critical for throughput as it allows multiple requests to be
+            // processed simultaneously, especially important since image generation
+            // can take several seconds per request
+            tokio::spawn(async move {
+                // Hold the processing slot until the message is acked
+                let _permit = permit;
+                metrics::increment_gauge!("meme_generator_in_flight_requests", 1.0);
+
+                // Track total request count for capacity planning and monitoring
+                metrics::counter!("meme_generator_requests_total", 1);
+                let start = std::time::Instant::now();
+
+                // Register before touching the status, so a cancellation arriving from here on
+                // reaches the task through the registry
+                let mut in_flight = state_clone.lock().await.in_flight.register(&req_id);
+
+                // Requests cancelled while still waiting in the stream are dropped unprocessed;
+                // a cancelled status is final, so the queued write reports it instead of replacing it
+                let status =
+                    update_status(&state_clone, &req_id, JobState::Queued, attempts, None).await;
+                if status.is_some_and(|status| status.state == JobState::Cancelled) {
+                    info!(request_id = %req_id, "Skipping cancelled request");
+                    metrics::counter!("meme_generator_cancelled_total", 1);
+                    drop(in_flight);
+                    if let Err(e) = message.ack().await {
+                        error!(request_id = %req_id, "Failed to ack message: {}", e);
+                    }
+                    metrics::decrement_gauge!("meme_generator_in_flight_requests", 1.0);
+                    return;
                 }
-                Err(e) => {
-                    // Error case - provide detailed error context and track failures
-                    // Including the error details in logs helps with troubleshooting
-                    error!(request_id = %req_id, error = %e, "Failed to process request");
-                    metrics::counter!("meme_generator_errors_total", 1);
-
-                    // Send explicit error response to the client
-                    // This provides clear feedback rather than silent failures
-                    // and enables better UX with appropriate error handling
-                    if let Err(e) = send_error_response(&state_clone, &req_id, e.to_string()).await
-                    {
-                        error!(request_id = %req_id, "Failed to send error response: {}", e);
+
+                // Call the main request processing function that handles image generation
+                // A cancellation drops the processing future, aborting any in-flight provider call
+                // Shutdown interrupts it the same way once the grace period is over
+                let outcome = tokio::select! {
+                    result = process_request(&state_clone, request.clone()) => TaskOutcome::Finished(result),
+                    _ = in_flight.cancelled() => TaskOutcome::Cancelled,
+                    _ = interrupt.wait_for(|interrupted| *interrupted) => TaskOutcome::Interrupted,
+                    never = send_progress(&message, &req_id, progress_interval) => match never {},
+                };
+                drop(in_flight);
+
+                // Record processing time for performance monitoring and SLA tracking
+                // This helps identify slow requests and performance degradation
+                let duration = start.elapsed().as_secs_f64();
+                metrics::histogram!("meme_generator_processing_duration_seconds", duration);
+
+                let mut ack = AckKind::Ack;
+                match outcome {
+                    TaskOutcome::Cancelled => {
+                        info!(request_id = %req_id, "Request cancelled");
+                        metrics::counter!("meme_generator_cancelled_total", 1);
+                        update_status(&state_clone, &req_id, JobState::Cancelled, None, None).await;
+                    }
+                    TaskOutcome::Interrupted => {
+                        // Hand the request straight back to the stream for another replica
+                        warn!(request_id = %req_id, "Shutting down, returning unfinished request");
+                        metrics::counter!("meme_generator_interrupted_total", 1);
+                        update_status(&state_clone, &req_id, JobState::Queued, None, None).await;
+                        ack = AckKind::Nak(None);
+                    }
+                    TaskOutcome::Finished(Ok(_)) => {
+                        // Success case - log and track for monitoring
+                        info!(request_id = %req_id, "Successfully processed request");
+                        metrics::counter!("meme_generator_success_total", 1);
+                        update_status(&state_clone, &req_id, JobState::Completed, None, None).await;
+                    }
+                    TaskOutcome::Finished(Err(e)) => {
+                        if e.downcast_ref::<LimitExceeded>().is_some() {
+                            // Rejected by a limit - expected and counted separately, not a failure
+                            info!(request_id = %req_id, "Rejected request: {}", e);
+                        } else {
+                            // Error case - provide detailed error context and track failures
+                            // Including the error details in logs helps with troubleshooting
+                            error!(request_id = %req_id, error = %e, "Failed to process request");
+                            metrics::counter!("meme_generator_errors_total", 1);
+                        }
+                        let error = Some(e.to_string());
+                        update_status(&state_clone, &req_id, JobState::Failed, None, error).await;
+
+                        // Send explicit error response to the client
+                        // This provides clear feedback rather than silent failures
+                        // and enables better UX with appropriate error handling
+                        if let Err(e) = send_error_response(&state_clone, &request, &e).await
+                        {
+                            error!(request_id = %req_id, "Failed to send error response: {}", e);
+                        }
                     }
                 }
-            }
 
-            // Acknowledge message only after all processing is complete
-            // This ensures at-least-once delivery semantics and prevents
-            // message loss in case of failures during processing
-            if let Err(e) = message.ack().await {
-                error!(request_id = %req_id, "Failed to ack message: {}", e);
-            }
-        });
+                // Acknowledge message only after all processing is complete
+                // This ensures at-least-once delivery semantics and prevents
+                // message loss in case of failures during processing
+                if let Err(e) = message.ack_with(ack).await {
+                    error!(request_id = %req_id, "Failed to ack message: {}", e);
+                }
+                metrics::decrement_gauge!("meme_generator_in_flight_requests", 1.0);
+            });
+        }
     }
 
+    // Requests still waiting in the fair queue go straight back to the stream
+    scheduler.release().await;
+
+    // Drain - Stop pulling, give in-flight requests the grace period to finish, then
+    // interrupt the rest so their messages are redelivered to another replica right away
+    let grace_period = Duration::from_secs(state.lock().await.config.shutdown_grace_period);
+    let in_flight = max_concurrency - semaphore.available_permits();
+    info!("Shutting down, waiting for {} in-flight requests (up to {:?})", in_flight, grace_period);
+
+    let all_slots = max_concurrency as u32;
+    if tokio::time::timeout(grace_period, semaphore.acquire_many(all_slots))
+        .await
+        .is_err()
+    {
+        let remaining = max_concurrency - semaphore.available_permits();
+        warn!("Grace period expired, returning {} unfinished requests to the stream", remaining);
+        interrupt_tx.send_replace(true);
+        if tokio::time::timeout(INTERRUPT_TIMEOUT, semaphore.acquire_many(all_slots))
+            .await
+            .is_err()
+        {
+            error!("Timed out waiting for interrupted requests to be returned");
+        }
+    }
+
+    info!("All in-flight requests finished");
     Ok(())
 }
 
+/// Sends in-progress acks every `interval` so JetStream resets the message's ack timer
+///
+/// Runs until dropped; the request task races it against the actual processing.
+async fn send_progress(
+    message: &jetstream::Message,
+    request_id: &str,
+    interval: Duration,
+) -> std::convert::Infallible {
+    let mut ticker = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    loop {
+        ticker.tick().await;
+        match message.ack_with(AckKind::Progress).await {
+            Ok(()) => {
+                debug!(request_id = %request_id, "Sent in-progress ack");
+                metrics::counter!("meme_generator_progress_acks_total", 1);
+            }
+            Err(e) => warn!(request_id = %request_id, "Failed to send in-progress ack: {}", e),
+        }
+    }
+}
+
+/// How a request task ended
+enum TaskOutcome {
+    /// `process_request` ran to completion, successfully or not
+    Finished(Result<()>),
+    /// The client cancelled the request
+    Cancelled,
+    /// The replica is shutting down and the request must be retried elsewhere
+    Interrupted,
+}
+
+/// Resolves when the process receives SIGTERM (Kubernetes pod termination) or Ctrl-C
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("Failed to listen for Ctrl-C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut signal) => {
+                signal.recv().await;
+            }
+            Err(e) => {
+                error!("Failed to listen for SIGTERM: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => info!("Received Ctrl-C"),
+        _ = terminate => info!("Received SIGTERM"),
+    }
+}
+
 #[instrument(skip(state), fields(request_id = %request.id))]
 /// Core image generation function that handles the entire meme creation pipeline
 ///
 /// This function implements a multi-stage process:
 /// 1. Check Redis cache first to avoid regenerating identical images
-/// 2. If not in cache, select the appropriate model based on request parameters
-/// 3. Format the prompt with specific instructions for better meme generation
-/// 4. Make the API request to Hugging Face with proper error handling
-/// 5. Cache the successful result to improve future response times
-/// 6. Deliver the response back to the client via NATS
+/// 2. If not in cache, generate a text-free image with the configured `ImageGenerator`
+/// 3. Cache the uncaptioned result so it can be reused with different captions
+/// 4. Draw the requested top/bottom captions onto the image
+/// 5. Deliver the response back to the client via NATS
 ///
+/// Redeliveries of a message that was already answered (a crash before the ack) skip all
+/// of this and republish the stored response.
 async fn process_request(state: &Arc<Mutex<AppState>>, request: MemeRequest) -> Result<()> {
     debug!(
         prompt = %request.prompt,
         "Processing meme request"
     );
 
+    let captions = [&request.top_text, &request.bottom_text].into_iter().flatten();
+    for caption in captions.chain(&request.text_boxes) {
+        if caption.chars().count() > MAX_CAPTION_LENGTH {
+            return Err(anyhow::anyhow!(
+                "Caption is too long (maximum {} characters)",
+                MAX_CAPTION_LENGTH
+            ));
+        }
+    }
+
+    if let Some(reply_to) = &request.reply_to {
+        if !is_valid_reply_token(reply_to) {
+            return Err(anyhow::anyhow!(
+                "Invalid reply_to '{}': use 1-128 letters, digits, '-' or '_'",
+                reply_to
+            ));
+        }
+    }
+
+    request.sampling.validate()?;
+    let limiter = state.lock().await.limiter.clone();
+    limiter.verify_client_ip(&request)?;
+
+    // Reject sizes no model can produce, models that may not be asked for by name and unknown
+    // styles; template memes use neither a model nor a style
+    let size = request.image_size()?;
+    if request.template.is_none() {
+        let (models, provider) = {
+            let state_guard = state.lock().await;
+            (state_guard.models.clone(), state_guard.config.image_provider)
+        };
+        // The registry only describes Hugging Face models; other providers draw any size
+        if provider == ImageProvider::HuggingFace {
+            models.candidates(request.model_class(), request.model.as_deref(), size)?;
+        }
+        state.lock().await.styles.get(request.style.as_deref())?;
+    }
+
+    // A redelivery of a request that was already answered gets the same response again
+    if let Some(response) = stored_result(state, &request).await {
+        info!("Request was already answered, republishing the stored response");
+        metrics::counter!("meme_generator_duplicate_deliveries_total", 1);
+        let response_data = serde_json::to_vec(&response)?;
+        return publish_response(state, &request, response_data.into()).await;
+    }
+
+    // Enforce the per-user, per-IP and global limits before doing any work
+    match limiter.check(&request).await {
+        Ok(None) => {}
+        Ok(Some(exceeded)) => {
+            metrics::counter!(
+                "meme_generator_rate_limited_total",
+                1,
+                "scope" => exceeded.scope,
+                "limit" => exceeded.kind.as_str()
+            );
+            return Err(exceeded.into());
+        }
+        Err(e) => {
+            // Fail open - losing Redis should not stop memes from being generated
+            warn!("Failed to check rate limits, allowing request: {:#}", e);
+            metrics::counter!("meme_generator_rate_limit_errors_total", 1);
+        }
+    }
+
+    // Template memes are composed locally and never touch the cache or the provider
+    if let Some(template) = &request.template {
+        update_status(state, &request.id, JobState::Generating, None, None).await;
+        let image_bytes = render_template(state, &request, template).await?;
+        let image = store_image(state, image_bytes).await?;
+        let response =
+            build_response(state, &request, image, None, SamplingParams::default()).await?;
+        return send_response(state, &request, response).await;
+    }
+
+    if request.prompt.trim().is_empty() {
+        return Err(anyhow::anyhow!("A prompt is required unless a template is selected"));
+    }
+    let style = state.lock().await.styles.get(request.style.as_deref())?;
+
     // Try to fetch from cache first
+    // Inline mode caches the base64 image, storage modes cache a reference to the stored object
     let state_guard = state.lock().await;
+    let inline = state_guard.store.is_none();
+    let cache_prefix = if inline { "meme" } else { "meme-ref" };
+    // Prompts are free text, so the fields are hashed rather than joined to keep keys unambiguous
+    let fingerprint = serde_json::to_vec(&(
+        &request.prompt,
+        request.fast_mode,
+        (size.width, size.height),
+        request.model.as_deref(),
+        style.cache_key(),
+        &request.sampling,
+    ))?;
     let cache_key = format!(
-        "meme:{}:{}:{}",
-        request.prompt, request.fast_mode, request.small_image
+        "{}:{}",
+        cache_prefix,
+        hex::encode(Sha256::digest(fingerprint))
     );
 
     let mut redis = state_guard.redis.clone();
     drop(state_guard);
 
     // Check cache
-    match redis.get::<_, Option<String>>(&cache_key).await {
+    let cached_data = match redis.get::<_, Option<String>>(&cache_key).await {
         Ok(Some(cached_data)) => {
             info!(
                 request_id = %request.id,
@@ -499,18 +1459,7 @@ async fn process_request(state: &Arc<Mutex<AppState>>, request: MemeRequest) ->
                 request.prompt
             );
             metrics::counter!("meme_generator_cache_hits_total", 1);
-
-            // Send cached response
-            let response = MemeResponse {
-                request_id: request.id.clone(),
-                image_data: cached_data,
-                prompt: request.prompt.clone(),
-                timestamp: std::time::SystemTime::now()
-                    .duration_since(std::time::UNIX_EPOCH)?
-                    .as_secs(),
-            };
-
-            return send_response(state, response).await;
+            Some(cached_data)
         }
         Ok(None) => {
             debug!(
@@ -519,6 +1468,7 @@ async fn process_request(state: &Arc<Mutex<AppState>>, request: MemeRequest) ->
                 request.prompt
             );
             metrics::counter!("meme_generator_cache_misses_total", 1);
+            None
         }
         Err(e) => {
             warn!(
@@ -527,181 +1477,362 @@ async fn process_request(state: &Arc<Mutex<AppState>>, request: MemeRequest) ->
                 e
             );
             metrics::counter!("meme_generator_cache_errors_total", 1);
+            None
         }
-    }
+    };
 
-    // Generate image
-    info!(
-        request_id = %request.id,
-        "Generating image for prompt: {}",
-        request.prompt
-    );
+    // Entries in an older format are treated as a miss and replaced
+    let cached_image = cached_data
+        .and_then(|cached_data| serde_json::from_str::<CachedImage>(&cached_data).ok())
+        .and_then(CachedImage::into_image);
 
-    let state_guard = state.lock().await;
-    let client = state_guard.http_client.clone();
-    let hf_api_token = state_guard.config.hf_api_token.clone();
-    let cache_ttl = state_guard.config.cache_ttl;
-    drop(state_guard);
+    let (image, model, sampling) = match cached_image {
+        Some((image, model, sampling)) => {
+            update_status(state, &request.id, JobState::Cached, None, None).await;
+            (image, model, sampling)
+        }
+        None => {
+            update_status(state, &request.id, JobState::Generating, None, None).await;
+            let generated = match generate_image(state, &request, size, &style).await {
+                Ok(generated) => generated,
+                Err(e) => {
+                    // Fall back to a template meme if one is configured and available
+                    let fallback = state.lock().await.config.fallback_template.clone();
+                    let Some(fallback) = fallback else {
+                        return Err(e);
+                    };
+                    warn!(
+                        request_id = %request.id,
+                        error = %e,
+                        "Image generation failed, falling back to template '{}'",
+                        fallback
+                    );
+                    metrics::counter!("meme_generator_template_fallbacks_total", 1);
+
+                    let image_bytes = render_template(state, &request, &fallback)
+                        .await
+                        .map_err(|fallback_error| {
+                            e.context(format!("Fallback template also failed: {:#}", fallback_error))
+                        })?;
+                    let image = store_image(state, image_bytes).await?;
+                    let response =
+                        build_response(state, &request, image, None, SamplingParams::default())
+                            .await?;
+                    return send_response(state, &request, response).await;
+                }
+            };
 
-    let start = std::time::Instant::now();
+            let image = store_image(state, generated.bytes).await?;
+            let model = Some(generated.model);
+            let sampling = generated.sampling;
+
+            // Cache the result
+            let cached_data =
+                serde_json::to_string(&CachedImage::new(&image, model.clone(), sampling.clone()))?;
+            let cache_ttl = state.lock().await.config.cache_ttl;
+            if let Err(e) = redis
+                .set_ex::<_, _, ()>(&cache_key, &cached_data, cache_ttl)
+                .await
+            {
+                warn!(
+                    request_id = %request.id,
+                    "Failed to cache image: {}",
+                    e
+                );
+            } else {
+                debug!(
+                    request_id = %request.id,
+                    "Cached image for prompt: {}",
+                    request.prompt
+                );
+            }
 
-    // Format the prompt for better meme generation with clearer text instructions
-    let full_prompt = format!(
-        "Funny meme image with cartoon style, vibrant colors. The image should have medium-sized, white text with black outline in impact font. \
-        The meme should be about: {}. \
-        The text should be short, funny, and placed at the top and bottom of the image in classic meme style. Make sure the text is not too large.", 
-        request.prompt
-    );
+            (image, model, sampling)
+        }
+    };
 
-    // Select the model based on parameters
-    let api_url = if request.fast_mode || request.small_image {
-        // Use the faster FLUX model when either fast_mode or small_image is selected
-        "https://router.huggingface.co/hf-inference/models/black-forest-labs/FLUX.1-schnell"
-            .to_string()
+    // Draw captions last so the cached base image is shared by every caption variant
+    let image = if request.top_text.is_some() || request.bottom_text.is_some() {
+        let renderer = state.lock().await.captions.clone();
+        let image_bytes = load_image(state, &image).await?;
+        let top_text = request.top_text.clone();
+        let bottom_text = request.bottom_text.clone();
+
+        // Rendering is CPU-bound, so keep it off the async worker threads
+        let captioned = tokio::task::spawn_blocking(move || {
+            renderer.render(&image_bytes, top_text.as_deref(), bottom_text.as_deref())
+        })
+        .await
+        .context("Caption rendering task failed")??;
+        store_image(state, Bytes::from(captioned)).await?
     } else {
-        // If neither fast_mode nor small_image is selected, check if env var is set
-        let state_guard = state.lock().await;
-        let env_url = state_guard.config.hf_api_url.clone();
-        drop(state_guard);
+        image
+    };
 
-        if env_url.is_empty() {
-            // Default to stable-diffusion-v1-5 if env var is empty
-            "https://api-inference.huggingface.co/models/runwayml/stable-diffusion-v1-5".to_string()
-        } else {
-            env_url
+    // Send response
+    let meme_response = build_response(state, &request, image, model, sampling).await?;
+    send_response(state, &request, meme_response).await
+}
+
+/// Listens on the cancel subject and cancels the requested memes
+///
+/// The payload is the request ID as plain text, or a JSON object with a `request_id` field.
+/// If this replica is processing the request, its task is signalled and records the
+/// cancellation itself. Requests no replica has picked up yet are marked cancelled in the
+/// status bucket so they are skipped on arrival. Requests running on another replica are
+/// left to that replica, which receives the same cancellation.
+async fn process_cancellations(
+    state: Arc<Mutex<AppState>>,
+    client: async_nats::Client,
+) -> Result<()> {
+    let subject = state.lock().await.config.cancel_subject.clone();
+    let mut cancellations = client
+        .subscribe(subject.clone())
+        .await
+        .with_context(|| format!("Failed to subscribe to {}", subject))?;
+    info!("Listening for cancellations on {}", subject);
+
+    while let Some(message) = cancellations.next().await {
+        let request_id = match serde_json::from_slice::<serde_json::Value>(&message.payload) {
+            Ok(serde_json::Value::Object(fields)) => fields
+                .get("request_id")
+                .and_then(|id| id.as_str())
+                .unwrap_or_default()
+                .to_string(),
+            _ => String::from_utf8_lossy(&message.payload).trim().to_string(),
+        };
+        if request_id.is_empty() {
+            warn!("Ignoring cancellation without a request ID");
+            continue;
+        }
+
+        let (in_flight, status) = {
+            let state_guard = state.lock().await;
+            (state_guard.in_flight.clone(), state_guard.status.clone())
+        };
+        if in_flight.cancel(&request_id) {
+            info!(request_id = %request_id, "Cancelling in-flight request");
+            continue;
+        }
+
+        // The request may still be in the stream (perhaps after an earlier delivery), in a fair
+        // queue or running elsewhere. Marking its record cancelled is compare-and-set, so a
+        // task either sees it when it writes its queued status or was registered before it.
+        match status.cancel(&request_id).await {
+            // It may have been registered here since the first check
+            Ok(true) if in_flight.cancel(&request_id) => {
+                info!(request_id = %request_id, "Cancelling in-flight request");
+            }
+            Ok(true) => info!(request_id = %request_id, "Marked request as cancelled"),
+            Ok(false) => debug!(request_id = %request_id, "Request has already finished"),
+            Err(e) => warn!(request_id = %request_id, "Failed to cancel request: {:#}", e),
         }
+    }
+
+    Ok(())
+}
+
+/// Records a state change for a request and returns the resulting record
+///
+/// Status tracking is best-effort: failures are logged and never fail the request itself.
+async fn update_status(
+    state: &Arc<Mutex<AppState>>,
+    request_id: &str,
+    job_state: JobState,
+    attempts: Option<u64>,
+    error: Option<String>,
+) -> Option<JobStatus> {
+    let status = state.lock().await.status.clone();
+    match status.update(request_id, job_state, attempts, error).await {
+        Ok(job) => Some(job),
+        Err(e) => {
+            warn!(request_id = %request_id, "Failed to update job status: {:#}", e);
+            None
+        }
+    }
+}
+
+/// Writes an image to the configured image store, or keeps it in memory in inline mode
+async fn store_image(state: &Arc<Mutex<AppState>>, bytes: Bytes) -> Result<ImageRef> {
+    let store = state.lock().await.store.clone();
+    let Some(store) = store else {
+        return Ok(ImageRef::Inline(bytes));
     };
 
-    // Create the request with parameters including image size
-    let image_size = if request.small_image { 512 } else { 1024 };
-    let parameters = serde_json::json!({
-        "width": image_size,
-        "height": image_size
-    });
+    let stored = storage::store_image(store.as_ref(), bytes).await?;
+    metrics::counter!("meme_generator_images_stored_total", 1);
+    debug!(key = %stored.key, "Stored image in {} storage", store.name());
+    Ok(ImageRef::Stored(stored))
+}
+
+/// Returns the bytes of an image, reading them back from the image store if needed
+async fn load_image(state: &Arc<Mutex<AppState>>, image: &ImageRef) -> Result<Bytes> {
+    match image {
+        ImageRef::Inline(bytes) => Ok(bytes.clone()),
+        ImageRef::Stored(stored) => {
+            let store = state
+                .lock()
+                .await
+                .store
+                .clone()
+                .context("Image store is not configured")?;
+            store.get(&stored.key).await
+        }
+    }
+}
 
-    let hf_request = HuggingFaceRequest {
-        inputs: full_prompt,
-        parameters: Some(parameters),
+/// Builds the response for an image, embedding it inline or referencing the stored object
+async fn build_response(
+    state: &Arc<Mutex<AppState>>,
+    request: &MemeRequest,
+    image: ImageRef,
+    model: Option<String>,
+    sampling: SamplingParams,
+) -> Result<MemeResponse> {
+    let (image_data, image_key, image_url, image_bucket, image_info) = match image {
+        ImageRef::Inline(bytes) => {
+            let info = ImageInfo::inspect(&bytes)?;
+            (Some(STANDARD.encode(&bytes)), None, None, None, info)
+        }
+        ImageRef::Stored(stored) => {
+            let store = state
+                .lock()
+                .await
+                .store
+                .clone()
+                .context("Image store is not configured")?;
+            let url = store.url(&stored.key).await?;
+            let bucket = store.bucket().map(str::to_string);
+            (None, Some(stored.key), url, bucket, stored.info)
+        }
     };
 
-    debug!(
-        request_id = %request.id,
-        "Full prompt sent to API: {}",
-        hf_request.inputs
-    );
+    Ok(MemeResponse {
+        request_id: request.id.clone(),
+        image_data,
+        image_key,
+        image_url,
+        image_bucket,
+        image_info,
+        model,
+        sampling,
+        prompt: request.prompt.clone(),
+        timestamp: std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)?
+            .as_secs(),
+    })
+}
 
-    // Add authorization header with the token
-    debug!(
+#[instrument(skip(state), fields(request_id = %request.id))]
+/// Generates a new, uncaptioned image for a request with the configured provider
+///
+/// The prompt is wrapped in the style's template, which also asks the model not to draw
+/// any text, since captions are rendered by the service afterwards.
+async fn generate_image(
+    state: &Arc<Mutex<AppState>>,
+    request: &MemeRequest,
+    size: ImageSize,
+    style: &Style,
+) -> Result<GeneratedImage> {
+    info!(
         request_id = %request.id,
-        "Sending request to Hugging Face API: {}",
-        api_url
+        style = %style.name,
+        "Generating image for prompt: {}",
+        request.prompt
     );
 
-    // Make the API request
-    let response = client
-        .post(&api_url)
-        .timeout(Duration::from_secs(60))
-        .header("Authorization", format!("Bearer {}", hf_api_token.trim()))
-        .json(&hf_request)
-        .send()
-        .await
-        .context("Failed to send request to Hugging Face API")?;
+    let generator = state.lock().await.generator.clone();
 
-    let status = response.status();
-    if !status.is_success() {
-        let error_text = response
-            .text()
-            .await
-            .unwrap_or_else(|_| "Unknown error".to_string());
-        error!(
-            request_id = %request.id,
-            status_code = %status.as_u16(),
-            "Hugging Face API error: {}",
-            error_text
-        );
-        return Err(anyhow::anyhow!(
-            "Hugging Face API error ({}): {}",
-            status,
-            error_text
-        ));
-    }
+    let start = std::time::Instant::now();
 
-    let image_bytes = response
-        .bytes()
-        .await
-        .context("Failed to get image bytes from response")?;
+    // Format the prompt for better meme generation, keeping the image free of text
+    let full_prompt = style.render(&request.prompt);
+
+    let params = GenerationParams {
+        user_prompt: request.prompt.clone(),
+        width: size.width,
+        height: size.height,
+        class: request.model_class(),
+        model: request.model.clone(),
+        sampling: request.sampling.clone(),
+    };
+
+    let image = generator.generate(&full_prompt, &params).await?;
 
     let generation_time = start.elapsed().as_secs_f64();
     metrics::histogram!(
         "meme_generator_generation_duration_seconds",
-        generation_time
+        generation_time,
+        "model" => image.model.clone()
     );
     info!(
         request_id = %request.id,
         duration_secs = generation_time,
-        model = if request.fast_mode { "fast" } else { "quality" },
-        image_size = image_bytes.len(),
-        resolution = image_size,
-        "Generated image in {} seconds ({} bytes, {}x{})",
+        provider = generator.name(),
+        model = %image.model,
+        seed = ?image.sampling.seed,
+        image_size = image.bytes.len(),
+        resolution = %size,
+        "Generated image in {} seconds ({} bytes, {})",
         generation_time,
-        image_bytes.len(),
-        image_size,
-        image_size
+        image.bytes.len(),
+        size
     );
 
-    // Encode image to base64
-    let image_data = STANDARD.encode(&image_bytes);
+    Ok(image)
+}
 
-    // Cache the result
-    if let Err(e) = redis
-        .set_ex::<_, _, ()>(&cache_key, &image_data, cache_ttl)
+#[instrument(skip(state, request), fields(request_id = %request.id))]
+/// Composes a template meme from the request's texts and returns the PNG bytes
+async fn render_template(
+    state: &Arc<Mutex<AppState>>,
+    request: &MemeRequest,
+    template_id: &str,
+) -> Result<Bytes> {
+    let state_guard = state.lock().await;
+    let template = state_guard
+        .templates
+        .get(template_id)
+        .with_context(|| format!("Unknown meme template '{}'", template_id))?;
+    let renderer = state_guard.captions.clone();
+    drop(state_guard);
+
+    let texts = request.template_texts();
+    let template_name = template.name.clone();
+
+    // Rendering is CPU-bound, so keep it off the async worker threads
+    let png = tokio::task::spawn_blocking(move || template.render(&renderer, &texts))
         .await
-    {
-        warn!(
-            request_id = %request.id,
-            "Failed to cache image: {}",
-            e
-        );
-    } else {
-        debug!(
-            request_id = %request.id,
-            "Cached image for prompt: {}",
-            request.prompt
-        );
-    }
+        .context("Template rendering task failed")??;
 
-    // Send response
-    let meme_response = MemeResponse {
-        request_id: request.id,
-        image_data,
-        prompt: request.prompt,
-        timestamp: std::time::SystemTime::now()
-            .duration_since(std::time::UNIX_EPOCH)?
-            .as_secs(),
-    };
+    metrics::counter!("meme_generator_template_renders_total", 1);
+    info!(
+        request_id = %request.id,
+        template = %template_name,
+        "Composed template meme ({} bytes)",
+        png.len()
+    );
 
-    send_response(state, meme_response).await
+    Ok(Bytes::from(png))
 }
 
 /// Publishes a successful meme generation response to the NATS message queue
 ///
 /// This function delivers the generated meme back to the client via NATS:
-/// 1. Acquires necessary configuration from shared state without holding the lock
-/// 2. Serializes the response to JSON format for transport
-/// 3. Publishes the message to the configured response subject
+/// 1. Serializes the response to JSON format for transport
+/// 2. Stores it in Redis under the message's stream position so redeliveries can republish it
+/// 3. Publishes the message to the request's reply subject (or the shared one)
 /// 4. Logs successful delivery for observability
-#[instrument(skip(state), fields(request_id = %response.request_id))]
-async fn send_response(state: &Arc<Mutex<AppState>>, response: MemeResponse) -> Result<()> {
-    let state_guard = state.lock().await;
-    let js = state_guard.js.clone();
-    let subject = state_guard.config.response_subject.clone();
-    drop(state_guard);
-
+#[instrument(skip(state, request), fields(request_id = %response.request_id))]
+async fn send_response(
+    state: &Arc<Mutex<AppState>>,
+    request: &MemeRequest,
+    response: MemeResponse,
+) -> Result<()> {
+    // Record the result first, so a redelivery after a crash past this point republishes it
+    record_result(state, request, &response).await;
     let response_data = serde_json::to_vec(&response)?;
-
-    js.publish(subject, response_data.into())
-        .await
-        .context("Failed to publish response")?;
+    publish_response(state, request, response_data.into()).await?;
 
     info!(
         request_id = %response.request_id,
@@ -712,27 +1843,157 @@ async fn send_response(state: &Arc<Mutex<AppState>>, response: MemeResponse) ->
     Ok(())
 }
 
+/// Publishes a serialized response to the request's reply subject
+async fn publish_response(
+    state: &Arc<Mutex<AppState>>,
+    request: &MemeRequest,
+    response_data: Bytes,
+) -> Result<()> {
+    let state_guard = state.lock().await;
+    let js = state_guard.js.clone();
+    let subject = request.response_subject(&state_guard.config);
+    drop(state_guard);
+
+    let headers = response_headers(request, "");
+    js.publish_with_headers(subject, headers, response_data)
+        .await
+        .context("Failed to publish response")?;
+    Ok(())
+}
+
+/// Headers for a response or error response
+///
+/// The message's stream position plus `suffix` is sent as `Nats-Msg-Id`, so a stream
+/// capturing response subjects stores a republished response only once within its
+/// duplicate window.
+fn response_headers(request: &MemeRequest, suffix: &str) -> async_nats::HeaderMap {
+    let mut headers = async_nats::HeaderMap::new();
+    if let Some(delivery) = &request.delivery {
+        headers.insert(
+            async_nats::header::NATS_MESSAGE_ID,
+            format!("{}{}", delivery, suffix),
+        );
+    }
+    headers
+}
+
+/// Redis key of the response stored for a request message
+fn result_key(delivery: &Delivery) -> String {
+    format!("meme-result:{}", delivery)
+}
+
+/// Returns the response already sent for this request message, if any
+///
+/// The image URL is signed again, since a presigned URL from the first delivery may have
+/// expired by now. Lookups are best-effort: if Redis or the store is unavailable the request
+/// is simply processed again.
+async fn stored_result(
+    state: &Arc<Mutex<AppState>>,
+    request: &MemeRequest,
+) -> Option<MemeResponse> {
+    let key = result_key(request.delivery.as_ref()?);
+    let (mut redis, store) = {
+        let state_guard = state.lock().await;
+        (state_guard.redis.clone(), state_guard.store.clone())
+    };
+    let data = match redis.get::<_, Option<Vec<u8>>>(key).await {
+        Ok(data) => data?,
+        Err(e) => {
+            warn!(request_id = %request.id, "Failed to look up stored response: {}", e);
+            return None;
+        }
+    };
+    let mut response: MemeResponse = match serde_json::from_slice(&data) {
+        Ok(response) => response,
+        Err(e) => {
+            warn!(request_id = %request.id, "Ignoring unreadable stored response: {}", e);
+            return None;
+        }
+    };
+    if let (Some(store), Some(image_key)) = (store, &response.image_key) {
+        match store.url(image_key).await {
+            Ok(url) => response.image_url = url,
+            Err(e) => {
+                warn!(request_id = %request.id, "Failed to get URL for stored response: {:#}", e);
+                return None;
+            }
+        }
+    }
+    Some(response)
+}
+
+/// Stores the response so redeliveries of the same message can republish it
+///
+/// Inline responses carry the whole image, which image storage exists to keep out of
+/// Redis, so only responses referencing a stored image are kept. The image URL is left out
+/// and rebuilt from the key on republish, as presigned URLs expire long before the result.
+async fn record_result(
+    state: &Arc<Mutex<AppState>>,
+    request: &MemeRequest,
+    response: &MemeResponse,
+) {
+    let Some(delivery) = &request.delivery else {
+        return;
+    };
+    let (mut redis, result_ttl, inline) = {
+        let state_guard = state.lock().await;
+        let inline = state_guard.store.is_none();
+        (state_guard.redis.clone(), state_guard.config.result_ttl, inline)
+    };
+    if inline {
+        return;
+    }
+    let stored = MemeResponse {
+        image_url: None,
+        ..response.clone()
+    };
+    let response_data = match serde_json::to_vec(&stored) {
+        Ok(data) => data,
+        Err(e) => {
+            warn!(request_id = %request.id, "Failed to serialize response for storage: {}", e);
+            return;
+        }
+    };
+    if let Err(e) = redis
+        .set_ex::<_, _, ()>(result_key(delivery), response_data, result_ttl)
+        .await
+    {
+        warn!(request_id = %request.id, "Failed to store response: {}", e);
+    }
+}
+
 /// Delivers error information back to the client when meme generation fails
 ///
 /// This function handles the error path in our messaging architecture:
-/// 1. Creates a dedicated error subject by appending `.error` to the response subject
-/// 2. Constructs a structured error response with timestamp and request ID
+/// 1. Creates a dedicated error subject by appending `.error` to the request's response subject
+/// 2. Constructs a structured error response with timestamp, request ID, error code and,
+///    for rejected requests, the number of seconds after which the client may retry
 /// 3. Publishes the error details to NATS so clients can display appropriate feedback
 /// 4. Logs the error response for later troubleshooting
-#[instrument(skip(state))]
+#[instrument(skip(state, request), fields(request_id = %request.id))]
 async fn send_error_response(
     state: &Arc<Mutex<AppState>>,
-    request_id: &str,
-    error: String,
+    request: &MemeRequest,
+    error: &anyhow::Error,
 ) -> Result<()> {
     let state_guard = state.lock().await;
     let js = state_guard.js.clone();
-    let subject = format!("{}.error", state_guard.config.response_subject);
+    let subject = format!("{}.error", request.response_subject(&state_guard.config));
     drop(state_guard);
+    let request_id = &request.id;
 
+    let (code, retry_after) = if let Some(exceeded) = error.downcast_ref::<LimitExceeded>() {
+        (exceeded.code(), Some(exceeded.retry_after))
+    } else if let Some(open) = error.downcast_ref::<CircuitOpen>() {
+        (ErrorCode::Unavailable, Some(open.retry_after.as_secs().max(1)))
+    } else {
+        (ErrorCode::Failed, None)
+    };
     let error_response = MemeError {
         request_id: request_id.to_string(),
-        error,
+        error: error.to_string(),
+        code,
+        retry_after,
         timestamp: std::time::SystemTime::now()
             .duration_since(std::time::UNIX_EPOCH)?
             .as_secs(),
@@ -740,7 +2001,8 @@ async fn send_error_response(
 
     let error_data = serde_json::to_vec(&error_response)?;
 
-    js.publish(subject, error_data.into())
+    let headers = response_headers(request, ".error");
+    js.publish_with_headers(subject, headers, error_data.into())
         .await
         .context("Failed to publish error response")?;
 
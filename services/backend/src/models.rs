@@ -0,0 +1,460 @@
+// ===== MODEL REGISTRY =====
+// Every model the service may call is described in one registry: its provider, endpoint,
+// supported sizes, default generation parameters, timeout and cost, plus the ordered chain
+// of models tried for each request class. The registry is read from the TOML file named by
+// `MODELS_FILE` at startup, so models can be added, swapped or disabled by editing that file
+// (a ConfigMap in Kubernetes) and restarting, without rebuilding the image. Clients may pick
+// a model by name, but only the ones marked `selectable`. Without a file the registry is
+// built from the `HF_*` settings, exactly like before it existed.
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fmt,
+    path::Path,
+    str::FromStr,
+    sync::Arc,
+};
+use tracing::warn;
+
+use crate::generator::{ImageProvider, ModelClass};
+use crate::Config;
+
+/// Fallback model used when `HF_API_URL` is explicitly set to an empty string
+const DEFAULT_QUALITY_URL: &str =
+    "https://api-inference.huggingface.co/models/runwayml/stable-diffusion-v1-5";
+
+/// Endpoint prefix for models given by ID rather than URL
+const MODELS_BASE_URL: &str = "https://router.huggingface.co/hf-inference/models/";
+
+/// Smallest and largest width or height clients may ask for
+const MIN_DIMENSION: u32 = 256;
+const MAX_DIMENSION: u32 = 2048;
+
+/// Diffusion models work in latent blocks, so dimensions must be multiples of this
+const DIMENSION_STEP: u32 = 16;
+
+/// Image dimensions in pixels, written as `<width>x<height>`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize)]
+#[serde(try_from = "String")]
+pub struct ImageSize {
+    pub width: u32,
+    pub height: u32,
+}
+
+impl ImageSize {
+    /// Checks dimensions a client asked for
+    pub fn checked(width: u32, height: u32) -> Result<Self> {
+        for dimension in [width, height] {
+            if !(MIN_DIMENSION..=MAX_DIMENSION).contains(&dimension)
+                || dimension % DIMENSION_STEP != 0
+            {
+                bail!(
+                    "Invalid image size {}x{}: width and height must be multiples of {} between {} and {}",
+                    width,
+                    height,
+                    DIMENSION_STEP,
+                    MIN_DIMENSION,
+                    MAX_DIMENSION
+                );
+            }
+        }
+        Ok(Self { width, height })
+    }
+}
+
+impl TryFrom<String> for ImageSize {
+    type Error = String;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        let parsed = value.split_once('x').and_then(|(width, height)| {
+            Some((width.trim().parse().ok()?, height.trim().parse().ok()?))
+        });
+        match parsed {
+            Some((width, height)) if width > 0 && height > 0 => Ok(Self { width, height }),
+            _ => Err(format!("invalid size '{}', expected e.g. 1024x1024", value)),
+        }
+    }
+}
+
+impl fmt::Display for ImageSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}x{}", self.width, self.height)
+    }
+}
+
+/// Aspect ratio presets clients can ask for instead of exact dimensions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AspectRatio {
+    /// 1:1
+    Square,
+    /// 4:3
+    Landscape,
+    /// 3:4
+    Portrait,
+    /// 16:9, for sharing in feeds
+    Wide,
+    /// 9:16, for stories and short videos
+    Tall,
+}
+
+impl AspectRatio {
+    /// Every preset, in the order they are listed to clients
+    const ALL: [AspectRatio; 5] = [
+        AspectRatio::Square,
+        AspectRatio::Landscape,
+        AspectRatio::Portrait,
+        AspectRatio::Wide,
+        AspectRatio::Tall,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AspectRatio::Square => "1:1",
+            AspectRatio::Landscape => "4:3",
+            AspectRatio::Portrait => "3:4",
+            AspectRatio::Wide => "16:9",
+            AspectRatio::Tall => "9:16",
+        }
+    }
+
+    /// Dimensions for the preset, with a long side of 1024 pixels or 512 for small images
+    pub fn size(self, small: bool) -> ImageSize {
+        let (width, height) = match self {
+            AspectRatio::Square => (1024, 1024),
+            AspectRatio::Landscape => (1024, 768),
+            AspectRatio::Portrait => (768, 1024),
+            AspectRatio::Wide => (1024, 576),
+            AspectRatio::Tall => (576, 1024),
+        };
+        let scale = if small { 2 } else { 1 };
+        ImageSize {
+            width: width / scale,
+            height: height / scale,
+        }
+    }
+}
+
+impl FromStr for AspectRatio {
+    type Err = anyhow::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|ratio| ratio.as_str() == value.trim())
+            .with_context(|| {
+                let presets: Vec<&str> = Self::ALL.iter().map(|ratio| ratio.as_str()).collect();
+                format!(
+                    "Unknown aspect ratio '{}', choose one of: {}",
+                    value,
+                    presets.join(", ")
+                )
+            })
+    }
+}
+
+/// One model in the registry
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ModelSpec {
+    /// Registry name, used in requests, logs, metrics and responses
+    #[serde(skip)]
+    pub name: String,
+    /// Provider serving the model
+    pub provider: ImageProvider,
+    /// Endpoint URL, or a model ID on the Hugging Face inference router
+    pub url: String,
+    /// Sizes the model can generate; empty means any
+    #[serde(default)]
+    pub sizes: Vec<ImageSize>,
+    /// Parameters sent with every request to this model, e.g. `num_inference_steps`
+    #[serde(default)]
+    pub parameters: serde_json::Map<String, serde_json::Value>,
+    /// Seconds a single call may take before it is abandoned
+    #[serde(default = "default_timeout_secs")]
+    pub timeout_secs: u64,
+    /// Estimated cost of one image, in whatever unit the deployment budgets in
+    #[serde(default)]
+    pub cost: f64,
+    /// Whether clients may request this model by name
+    #[serde(default)]
+    pub selectable: bool,
+}
+
+fn default_timeout_secs() -> u64 {
+    60
+}
+
+impl ModelSpec {
+    /// Creates an unselectable Hugging Face model with default settings
+    fn huggingface(entry: &str) -> Self {
+        let url = model_url(entry);
+        Self {
+            name: url.rsplit("/models/").next().unwrap_or(&url).to_string(),
+            provider: ImageProvider::HuggingFace,
+            url,
+            sizes: Vec::new(),
+            parameters: serde_json::Map::new(),
+            timeout_secs: default_timeout_secs(),
+            cost: 0.0,
+            selectable: false,
+        }
+    }
+
+    /// Whether the model can generate images of the given size
+    pub fn supports(&self, size: ImageSize) -> bool {
+        self.sizes.is_empty() || self.sizes.contains(&size)
+    }
+}
+
+/// Resolves a model ID or a full endpoint URL to an endpoint URL
+fn model_url(entry: &str) -> String {
+    if entry.contains("://") {
+        entry.to_string()
+    } else {
+        format!("{}{}", MODELS_BASE_URL, entry.trim_matches('/'))
+    }
+}
+
+/// On-disk format of the registry file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RegistryFile {
+    classes: ClassChains,
+    models: BTreeMap<String, ModelSpec>,
+}
+
+/// Model names tried in order for each request class
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ClassChains {
+    quality: Vec<String>,
+    /// Defaults to the quality chain
+    #[serde(default)]
+    fast: Vec<String>,
+    /// Deprecated: small images use the quality or fast chain like any other size. Still
+    /// accepted so registry files written for older versions keep loading.
+    #[serde(default)]
+    small: Option<Vec<String>>,
+}
+
+/// Error returned for requests naming a model they may not use
+#[derive(Debug)]
+pub struct UnknownModel {
+    pub name: String,
+    /// Models clients may pick from
+    pub allowed: Vec<String>,
+}
+
+impl fmt::Display for UnknownModel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Model '{}' is not available", self.name)?;
+        if self.allowed.is_empty() {
+            write!(f, ", model selection is disabled")
+        } else {
+            write!(f, ", choose one of: {}", self.allowed.join(", "))
+        }
+    }
+}
+
+impl std::error::Error for UnknownModel {}
+
+/// All models the service may call and the chains they are tried in
+#[derive(Debug)]
+pub struct ModelRegistry {
+    models: BTreeMap<String, Arc<ModelSpec>>,
+    quality: Vec<Arc<ModelSpec>>,
+    fast: Vec<Arc<ModelSpec>>,
+}
+
+impl ModelRegistry {
+    /// Loads the registry from `MODELS_FILE`, or builds it from the `HF_*` settings
+    pub fn load(config: &Config) -> Result<Self> {
+        if !config.hf_small_models.is_empty() {
+            warn!("HF_SMALL_MODELS is deprecated and ignored, small images use the quality or fast chain");
+        }
+        match &config.models_file {
+            Some(path) => Self::from_file(path),
+            None => Ok(Self::from_config(config)),
+        }
+    }
+
+    /// Reads and validates a registry file
+    fn from_file(path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read model registry {}", path.display()))?;
+        let file: RegistryFile = toml::from_str(&contents)
+            .with_context(|| format!("Failed to parse model registry {}", path.display()))?;
+
+        let models: BTreeMap<String, Arc<ModelSpec>> = file
+            .models
+            .into_iter()
+            .map(|(name, mut spec)| {
+                spec.name = name.clone();
+                spec.url = model_url(spec.url.trim());
+                (name, Arc::new(spec))
+            })
+            .collect();
+        for spec in models.values() {
+            if spec.provider != ImageProvider::HuggingFace {
+                bail!(
+                    "Model '{}' uses an unsupported provider, only huggingface models can be registered",
+                    spec.name
+                );
+            }
+            if spec.timeout_secs == 0 {
+                bail!("Model '{}' needs a timeout_secs above 0", spec.name);
+            }
+        }
+
+        let resolve = |class: &str, names: &[String]| -> Result<Vec<Arc<ModelSpec>>> {
+            names
+                .iter()
+                .map(|name| {
+                    models.get(name).cloned().with_context(|| {
+                        format!("The {} chain lists unknown model '{}'", class, name)
+                    })
+                })
+                .collect()
+        };
+        let chains = file.classes;
+        if chains.small.is_some() {
+            warn!(
+                "classes.small in {} is deprecated and ignored, small images use the quality or fast chain",
+                path.display()
+            );
+        }
+        if chains.quality.is_empty() {
+            bail!("The model registry needs at least one model in classes.quality");
+        }
+        let quality = resolve("quality", &chains.quality)?;
+        let fast = if chains.fast.is_empty() {
+            quality.clone()
+        } else {
+            resolve("fast", &chains.fast)?
+        };
+
+        Ok(Self {
+            models,
+            quality,
+            fast,
+        })
+    }
+
+    /// Builds the registry from the `HF_*` endpoint and chain settings
+    ///
+    /// None of these models can be selected by clients.
+    fn from_config(config: &Config) -> Self {
+        let quality_url = if config.hf_api_url.is_empty() {
+            DEFAULT_QUALITY_URL
+        } else {
+            config.hf_api_url.as_str()
+        };
+
+        // Without explicit chains each class keeps using its single configured endpoint
+        let chain = |models: &[String], default: &str| -> Vec<String> {
+            let entries: Vec<String> = models
+                .iter()
+                .map(|model| model.trim())
+                .filter(|model| !model.is_empty())
+                .map(str::to_string)
+                .collect();
+            if entries.is_empty() {
+                vec![default.to_string()]
+            } else {
+                entries
+            }
+        };
+
+        // A model used by several classes is registered once
+        let mut models: BTreeMap<String, Arc<ModelSpec>> = BTreeMap::new();
+        let mut by_url: HashMap<String, Arc<ModelSpec>> = HashMap::new();
+        let mut resolve = |entries: Vec<String>| -> Vec<Arc<ModelSpec>> {
+            entries
+                .iter()
+                .map(|entry| {
+                    by_url
+                        .entry(model_url(entry))
+                        .or_insert_with(|| {
+                            let spec = Arc::new(ModelSpec::huggingface(entry));
+                            models.insert(spec.name.clone(), spec.clone());
+                            spec
+                        })
+                        .clone()
+                })
+                .collect()
+        };
+        let quality = resolve(chain(&config.hf_quality_models, quality_url));
+        let fast = resolve(chain(&config.hf_fast_models, &config.hf_fast_api_url));
+
+        Self {
+            models,
+            quality,
+            fast,
+        }
+    }
+
+    /// Number of registered models
+    pub fn len(&self) -> usize {
+        self.models.len()
+    }
+
+    /// All registered models, by name
+    pub fn models(&self) -> impl Iterator<Item = &Arc<ModelSpec>> {
+        self.models.values()
+    }
+
+    /// Models tried, in order, for a request class
+    pub fn chain(&self, class: ModelClass) -> &[Arc<ModelSpec>] {
+        match class {
+            ModelClass::Quality => &self.quality,
+            ModelClass::Fast => &self.fast,
+        }
+    }
+
+    /// Models to try, in order, for an image of the given size
+    ///
+    /// A model the client picked is used on its own; otherwise the class's chain is used,
+    /// leaving out models that cannot produce the size.
+    pub fn candidates(
+        &self,
+        class: ModelClass,
+        model: Option<&str>,
+        size: ImageSize,
+    ) -> Result<Vec<Arc<ModelSpec>>> {
+        let specs = match model {
+            Some(name) => vec![self.selectable(name)?.clone()],
+            None => self.chain(class).to_vec(),
+        };
+        let supported: Vec<Arc<ModelSpec>> = specs
+            .iter()
+            .filter(|spec| spec.supports(size))
+            .cloned()
+            .collect();
+        if supported.is_empty() {
+            let names: Vec<&str> = specs.iter().map(|spec| spec.name.as_str()).collect();
+            bail!(
+                "Image size {} is not supported by {}",
+                size,
+                names.join(", ")
+            );
+        }
+        Ok(supported)
+    }
+
+    /// Looks up a model a client asked for, which must be marked `selectable`
+    pub fn selectable(&self, name: &str) -> Result<&Arc<ModelSpec>, UnknownModel> {
+        match self.models.get(name) {
+            Some(spec) if spec.selectable => Ok(spec),
+            _ => Err(UnknownModel {
+                name: name.to_string(),
+                allowed: self
+                    .models()
+                    .filter(|spec| spec.selectable)
+                    .map(|spec| spec.name.clone())
+                    .collect(),
+            }),
+        }
+    }
+}
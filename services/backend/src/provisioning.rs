@@ -0,0 +1,270 @@
+// ===== JETSTREAM PROVISIONING =====
+// The service creates the streams and the durable consumer it needs on startup. When they
+// already exist, their live configuration is compared with the one the service expects, so
+// changes to subjects, ack wait, max deliveries and similar settings take effect on a running
+// cluster without deleting anything by hand. Fields JetStream can update in place are updated;
+// fields that would need the stream or consumer to be recreated are reported according to
+// `RECONCILE_MODE`. Only the fields the service manages are compared, so settings tuned by
+// operators (replicas, limits, ...) are left alone.
+
+use anyhow::{bail, Context, Result};
+use async_nats::jetstream::{
+    self,
+    consumer::{self, pull, FromConsumer},
+    stream,
+};
+use clap::ValueEnum;
+use std::fmt::Debug;
+use tracing::{info, warn};
+
+/// What to do when a live setting differs in a way that cannot be updated in place
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ReconcileMode {
+    /// Log the difference and keep running with the live setting
+    Warn,
+    /// Refuse to start until the stream or consumer is recreated
+    Fail,
+}
+
+/// One setting that differs between the live and the desired configuration
+struct Drift {
+    field: &'static str,
+    live: String,
+    desired: String,
+}
+
+/// Differences found while comparing a live configuration with the desired one
+#[derive(Default)]
+struct Diff {
+    /// Settings JetStream can change in place
+    updatable: Vec<Drift>,
+    /// Settings that require deleting and recreating the stream or consumer
+    immutable: Vec<Drift>,
+}
+
+impl Diff {
+    fn compare<T: PartialEq + Debug>(&mut self, field: &'static str, live: &T, desired: &T) {
+        self.push(false, field, live, desired);
+    }
+
+    fn compare_immutable<T: PartialEq + Debug>(
+        &mut self,
+        field: &'static str,
+        live: &T,
+        desired: &T,
+    ) {
+        self.push(true, field, live, desired);
+    }
+
+    fn push<T: PartialEq + Debug>(
+        &mut self,
+        immutable: bool,
+        field: &'static str,
+        live: &T,
+        desired: &T,
+    ) {
+        if live == desired {
+            return;
+        }
+        let drift = Drift {
+            field,
+            live: format!("{:?}", live),
+            desired: format!("{:?}", desired),
+        };
+        if immutable {
+            self.immutable.push(drift);
+        } else {
+            self.updatable.push(drift);
+        }
+    }
+
+    /// Reports settings that need a recreate, failing in `fail` mode
+    fn check_immutable(&self, kind: &str, name: &str, mode: ReconcileMode) -> Result<()> {
+        if self.immutable.is_empty() {
+            return Ok(());
+        }
+        let changes = self
+            .immutable
+            .iter()
+            .map(|drift| {
+                format!(
+                    "{} is {} but {} is configured",
+                    drift.field, drift.live, drift.desired
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("; ");
+        match mode {
+            ReconcileMode::Warn => {
+                warn!(
+                    "{} {} must be recreated to apply: {}. Keeping the live settings",
+                    kind, name, changes
+                );
+                Ok(())
+            }
+            ReconcileMode::Fail => bail!(
+                "{} {} must be recreated to apply: {}. Delete it or set RECONCILE_MODE=warn",
+                kind,
+                name,
+                changes
+            ),
+        }
+    }
+
+    fn log_updates(&self, kind: &str, name: &str) {
+        for drift in &self.updatable {
+            info!(
+                "Updating {} of {} {}: {} -> {}",
+                drift.field, kind, name, drift.live, drift.desired
+            );
+        }
+    }
+}
+
+/// Returns the stream described by `desired`, creating or reconciling it as needed
+///
+/// Subjects, max age and the description (when one is configured) are updated in place;
+/// retention and storage type can only be changed by recreating the stream.
+pub async fn ensure_stream(
+    js: &jetstream::Context,
+    desired: stream::Config,
+    mode: ReconcileMode,
+) -> Result<stream::Stream> {
+    let name = desired.name.clone();
+    let mut stream = match js.get_stream(&name).await {
+        Ok(stream) => {
+            info!("Found existing stream: {}", name);
+            stream
+        }
+        Err(_) => {
+            info!(
+                "Creating new stream: {} for subjects: {:?}",
+                name, desired.subjects
+            );
+            return js
+                .create_stream(desired)
+                .await
+                .with_context(|| format!("Failed to create stream {}", name));
+        }
+    };
+
+    let live = stream.cached_info().config.clone();
+    let mut diff = Diff::default();
+    diff.compare(
+        "subjects",
+        &sorted(&live.subjects),
+        &sorted(&desired.subjects),
+    );
+    if desired.description.is_some() {
+        diff.compare("description", &live.description, &desired.description);
+    }
+    diff.compare("max_age", &live.max_age, &desired.max_age);
+    diff.compare_immutable("retention", &live.retention, &desired.retention);
+    diff.compare_immutable("storage", &live.storage, &desired.storage);
+
+    diff.check_immutable("Stream", &name, mode)?;
+    if diff.updatable.is_empty() {
+        return Ok(stream);
+    }
+
+    diff.log_updates("stream", &name);
+    let updated = stream::Config {
+        subjects: desired.subjects,
+        description: desired.description.or(live.description.clone()),
+        max_age: desired.max_age,
+        ..live
+    };
+    js.update_stream(&updated)
+        .await
+        .with_context(|| format!("Failed to update stream {}", name))?;
+    stream
+        .info()
+        .await
+        .with_context(|| format!("Failed to refresh stream {}", name))?;
+    Ok(stream)
+}
+
+/// Returns the durable pull consumer described by `desired`, creating or reconciling it
+///
+/// Filter subjects, ack wait, max deliveries and max ack pending are updated in place; the
+/// deliver and ack policies can only be changed by recreating the consumer.
+pub async fn ensure_consumer(
+    stream: &stream::Stream,
+    desired: pull::Config,
+    mode: ReconcileMode,
+) -> Result<consumer::Consumer<pull::Config>> {
+    let name = desired
+        .durable_name
+        .clone()
+        .context("The consumer must be durable")?;
+    let consumer: consumer::Consumer<pull::Config> = match stream.get_consumer(&name).await {
+        Ok(consumer) => consumer,
+        Err(_) => {
+            info!("Creating new consumer: {}", name);
+            return stream
+                .create_consumer(desired)
+                .await
+                .with_context(|| format!("Failed to create consumer {}", name));
+        }
+    };
+
+    let live = consumer.cached_info().config.clone();
+    let mut diff = Diff::default();
+    diff.compare(
+        "filter_subjects",
+        &sorted(&filter_subjects(
+            &live.filter_subject,
+            &live.filter_subjects,
+        )),
+        &sorted(&filter_subjects(
+            &desired.filter_subject,
+            &desired.filter_subjects,
+        )),
+    );
+    diff.compare("ack_wait", &live.ack_wait, &desired.ack_wait);
+    diff.compare("max_deliver", &live.max_deliver, &desired.max_deliver);
+    diff.compare(
+        "max_ack_pending",
+        &live.max_ack_pending,
+        &desired.max_ack_pending,
+    );
+    diff.compare_immutable(
+        "deliver_policy",
+        &live.deliver_policy,
+        &desired.deliver_policy,
+    );
+    diff.compare_immutable("ack_policy", &live.ack_policy, &desired.ack_policy);
+
+    diff.check_immutable("Consumer", &name, mode)?;
+    if diff.updatable.is_empty() {
+        return Ok(consumer);
+    }
+
+    diff.log_updates("consumer", &name);
+    let mut updated = pull::Config::try_from_consumer_config(live)
+        .map_err(|e| anyhow::anyhow!("Consumer {} is not a pull consumer: {}", name, e))?;
+    updated.filter_subject = desired.filter_subject;
+    updated.filter_subjects = desired.filter_subjects;
+    updated.ack_wait = desired.ack_wait;
+    updated.max_deliver = desired.max_deliver;
+    updated.max_ack_pending = desired.max_ack_pending;
+    stream
+        .update_consumer(updated)
+        .await
+        .with_context(|| format!("Failed to update consumer {}", name))
+}
+
+/// Filter subjects of a consumer, whichever of the two fields they are set in
+fn filter_subjects(filter_subject: &str, filter_subjects: &[String]) -> Vec<String> {
+    if filter_subject.is_empty() {
+        filter_subjects.to_vec()
+    } else {
+        vec![filter_subject.to_string()]
+    }
+}
+
+fn sorted(subjects: &[String]) -> Vec<String> {
+    let mut subjects = subjects.to_vec();
+    subjects.sort();
+    subjects
+}
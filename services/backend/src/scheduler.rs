@@ -0,0 +1,293 @@
+// ===== PRIORITY LANES =====
+// Requests can be published to one of several lanes, e.g. `meme.request.high` and
+// `meme.request.normal`, each with its own durable consumer on the request stream. Whenever
+// processing slots free up, the scheduler splits them across the lanes by weight (smooth
+// weighted round-robin), so a backlog of slow requests in one lane cannot starve another,
+// while the total number of requests in flight still never exceeds `MAX_CONCURRENCY`.
+// Slots a lane cannot fill because it is empty go to the other lanes. Each replica also keeps
+// up to `FAIR_QUEUE_DEPTH` pulled requests waiting, so that within a lane free slots can be
+// handed to users in turn (see `fairness`) rather than strictly in arrival order. Those
+// requests are ack-pending and no longer count as lag for KEDA, which is why the depth is
+// kept small: fairness only covers this window, not the whole backlog in the stream.
+
+use anyhow::{bail, Result};
+use async_nats::jetstream::{self, consumer::pull, AckKind};
+use futures::{stream::SelectAll, StreamExt};
+use std::{str::FromStr, time::Duration};
+use tracing::{debug, error, warn};
+
+use crate::{
+    fairness::{self, FairQueue},
+    limits::GATEWAY_TOKEN_HEADER,
+    Delivery, MemeRequest,
+};
+
+/// How long an idle scheduler waits before polling the lanes again without a wake-up
+///
+/// Catches messages that become available without a publish, e.g. redeliveries.
+const IDLE_POLL: Duration = Duration::from_secs(1);
+
+/// A lane as configured in `REQUEST_LANES`: `name:weight`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LaneSpec {
+    pub name: String,
+    pub weight: u32,
+}
+
+impl FromStr for LaneSpec {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<Self, Self::Err> {
+        let (name, weight) = match spec.trim().split_once(':') {
+            Some((name, weight)) => (name, weight),
+            None => (spec.trim(), "1"),
+        };
+        if name.is_empty()
+            || !name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        {
+            return Err(format!(
+                "invalid lane name '{}': use letters, digits, '-' or '_'",
+                name
+            ));
+        }
+        let weight = weight
+            .parse::<u32>()
+            .ok()
+            .filter(|weight| *weight > 0)
+            .ok_or_else(|| format!("invalid weight '{}' for lane {}", weight, name))?;
+        Ok(Self {
+            name: name.to_string(),
+            weight,
+        })
+    }
+}
+
+/// Checks that lane names are unique and that the default lane is one of them
+pub fn validate_lanes(lanes: &[LaneSpec], default_lane: &str) -> Result<()> {
+    if lanes.is_empty() {
+        bail!("At least one request lane is required");
+    }
+    for (index, lane) in lanes.iter().enumerate() {
+        if lanes[..index].iter().any(|other| other.name == lane.name) {
+            bail!("Request lane '{}' is configured twice", lane.name);
+        }
+    }
+    if !lanes.iter().any(|lane| lane.name == default_lane) {
+        bail!(
+            "Default lane '{}' is not one of REQUEST_LANES",
+            default_lane
+        );
+    }
+    Ok(())
+}
+
+/// A lane's consumer and the requests pulled from it that are waiting for a slot
+pub struct Lane {
+    name: String,
+    weight: i64,
+    consumer: jetstream::consumer::Consumer<pull::Config>,
+    /// Pulled requests, queued per user
+    queue: FairQueue<Pulled>,
+}
+
+impl Lane {
+    pub fn new(spec: &LaneSpec, consumer: jetstream::consumer::Consumer<pull::Config>) -> Self {
+        Self {
+            name: spec.name.clone(),
+            weight: i64::from(spec.weight),
+            consumer,
+            queue: FairQueue::default(),
+        }
+    }
+}
+
+/// A request pulled from a lane
+pub struct Pulled {
+    pub lane: String,
+    pub message: jetstream::Message,
+    pub request: MemeRequest,
+}
+
+/// Smooth weighted round-robin over the lanes
+#[derive(Default)]
+struct WeightedRoundRobin {
+    credits: Vec<i64>,
+}
+
+impl WeightedRoundRobin {
+    /// Picks the next lane among the eligible ones, in proportion to their weights
+    fn pick(&mut self, lanes: &[Lane], eligible: impl Fn(usize) -> bool) -> Option<usize> {
+        self.credits.resize(lanes.len(), 0);
+        let mut total = 0;
+        let mut best: Option<usize> = None;
+        for (index, lane) in lanes.iter().enumerate() {
+            if !eligible(index) {
+                continue;
+            }
+            self.credits[index] += lane.weight;
+            total += lane.weight;
+            match best {
+                Some(best) if self.credits[best] >= self.credits[index] => {}
+                _ => best = Some(index),
+            }
+        }
+        let index = best?;
+        self.credits[index] -= total;
+        Some(index)
+    }
+}
+
+/// Splits processing slots across the lanes by weight, and across users within a lane
+pub struct Scheduler {
+    lanes: Vec<Lane>,
+    /// Decides which lanes messages are pulled from
+    fetch_order: WeightedRoundRobin,
+    /// Decides which lane's queued request gets the next free slot
+    dispatch_order: WeightedRoundRobin,
+    /// Plain subscriptions to the lane subjects, used to wake up an idle scheduler
+    wake: SelectAll<async_nats::Subscriber>,
+}
+
+impl Scheduler {
+    pub fn new(lanes: Vec<Lane>, wake: Vec<async_nats::Subscriber>) -> Self {
+        Self {
+            lanes,
+            fetch_order: WeightedRoundRobin::default(),
+            dispatch_order: WeightedRoundRobin::default(),
+            wake: futures::stream::select_all(wake),
+        }
+    }
+
+    /// Number of pulled requests waiting for a slot
+    pub fn queued(&self) -> usize {
+        self.lanes.iter().map(|lane| lane.queue.len()).sum()
+    }
+
+    /// Pulls up to `count` messages that are already waiting, split across the lanes by weight
+    ///
+    /// Never waits for new messages. Malformed messages are acked and dropped.
+    pub async fn fill(&mut self, count: usize) {
+        let mut pulled = 0;
+        let mut exhausted = vec![false; self.lanes.len()];
+
+        while pulled < count && exhausted.contains(&false) {
+            // Hand out the remaining slots one by one, then fetch each lane's share at once
+            let mut quotas = vec![0; self.lanes.len()];
+            for _ in pulled..count {
+                if let Some(index) = self.fetch_order.pick(&self.lanes, |i| !exhausted[i]) {
+                    quotas[index] += 1;
+                }
+            }
+
+            for (index, quota) in quotas.into_iter().enumerate() {
+                if quota == 0 {
+                    continue;
+                }
+                let lane = &mut self.lanes[index];
+                let messages = fetch_lane(lane, quota).await;
+                if messages.len() < quota {
+                    exhausted[index] = true;
+                }
+                debug!(lane = %lane.name, "Pulled {} of {} messages", messages.len(), quota);
+                pulled += messages.len();
+
+                for message in messages {
+                    // Parse the message payload into a strongly-typed MemeRequest
+                    // We acknowledge malformed messages to remove them from the queue
+                    // to prevent infinite redelivery of unparseable messages
+                    let mut request: MemeRequest = match serde_json::from_slice(&message.payload) {
+                        Ok(request) => request,
+                        Err(e) => {
+                            error!("Failed to parse message: {}", e);
+                            if let Err(e) = message.ack().await {
+                                error!("Failed to ack message: {}", e);
+                            }
+                            continue;
+                        }
+                    };
+                    request.delivery = Delivery::of(&message);
+                    request.gateway_token = message
+                        .headers
+                        .as_ref()
+                        .and_then(|headers| headers.get(GATEWAY_TOKEN_HEADER))
+                        .map(|token| token.to_string());
+                    let key = fairness::fairness_key(&request);
+                    let cost = fairness::request_cost(&request);
+                    let pulled = Pulled {
+                        lane: lane.name.clone(),
+                        message,
+                        request,
+                    };
+                    lane.queue.push(key, cost, pulled);
+                }
+            }
+        }
+        metrics::gauge!("meme_generator_queued_requests", self.queued() as f64);
+    }
+
+    /// Takes the request that gets the next free slot
+    ///
+    /// Lanes take turns by weight, and users take turns within a lane.
+    pub fn next(&mut self) -> Option<Pulled> {
+        let lanes = &self.lanes;
+        let index = self
+            .dispatch_order
+            .pick(lanes, |i| !lanes[i].queue.is_empty())?;
+        let pulled = self.lanes[index].queue.pop();
+        metrics::gauge!("meme_generator_queued_requests", self.queued() as f64);
+        pulled
+    }
+
+    /// Sends in-progress acks for queued requests so they are not redelivered while waiting
+    pub async fn keep_alive(&self) {
+        for lane in &self.lanes {
+            for pulled in lane.queue.iter() {
+                if let Err(e) = pulled.message.ack_with(AckKind::Progress).await {
+                    warn!(request_id = %pulled.request.id, "Failed to send in-progress ack: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Returns every queued request to the stream so another replica can pick it up
+    pub async fn release(&mut self) {
+        for lane in &mut self.lanes {
+            for pulled in lane.queue.drain() {
+                if let Err(e) = pulled.message.ack_with(AckKind::Nak(None)).await {
+                    warn!(request_id = %pulled.request.id, "Failed to return queued request: {}", e);
+                }
+            }
+        }
+        metrics::gauge!("meme_generator_queued_requests", 0.0);
+    }
+
+    /// Waits until a request is published to one of the lanes, or for the idle poll interval
+    pub async fn wait(&mut self) {
+        tokio::select! {
+            _ = self.wake.next() => {}
+            _ = tokio::time::sleep(IDLE_POLL) => {}
+        }
+    }
+}
+
+/// Fetches up to `max` messages from a lane without waiting for new ones
+async fn fetch_lane(lane: &Lane, max: usize) -> Vec<jetstream::Message> {
+    let mut batch = match lane.consumer.fetch().max_messages(max).messages().await {
+        Ok(batch) => batch,
+        Err(e) => {
+            error!(lane = %lane.name, "Failed to pull messages: {}", e);
+            return Vec::new();
+        }
+    };
+
+    let mut messages = Vec::new();
+    while let Some(message) = batch.next().await {
+        match message {
+            Ok(message) => messages.push(message),
+            Err(e) => error!(lane = %lane.name, "Error receiving message: {}", e),
+        }
+    }
+    messages
+}
@@ -0,0 +1,291 @@
+// ===== JOB STATUS TRACKING =====
+// Every request gets a status record in a JetStream KV bucket, keyed by request ID, that is
+// updated as the request moves through the pipeline. Records expire with the bucket's max
+// age. Anyone on the NATS network can look a request up by sending its ID to the status
+// subject, which makes "what happened to request X?" answerable without digging through logs.
+// Several writers can touch the same record (the processing task, a cancellation on another
+// replica, a redelivery), so updates are compare-and-set on the record's revision.
+
+use anyhow::{bail, Context, Result};
+use async_nats::jetstream::{self, kv};
+use bytes::Bytes;
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+/// Queue group for status lookups, so only one replica answers each query
+const STATUS_QUEUE_GROUP: &str = "meme-generator-status";
+
+/// Times a status write is retried after losing to a concurrent writer
+const MAX_UPDATE_ATTEMPTS: u32 = 5;
+
+/// Stage of a request in the generation pipeline
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobState {
+    /// Pulled from the stream, waiting to be processed
+    Queued,
+    /// An image is being generated or composed
+    Generating,
+    /// The base image was found in the Redis cache
+    Cached,
+    /// The response was published to the client
+    Completed,
+    /// Processing failed and an error was published to the client
+    Failed,
+    /// The client cancelled the request before it completed
+    Cancelled,
+}
+
+impl JobState {
+    /// Whether the request is done and its record will not change again
+    pub fn is_final(self) -> bool {
+        matches!(
+            self,
+            JobState::Completed | JobState::Failed | JobState::Cancelled
+        )
+    }
+}
+
+/// A single state change with the time it happened
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StateChange {
+    pub state: JobState,
+    pub timestamp: u64,
+}
+
+/// Status record for one request, as stored in the KV bucket
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobStatus {
+    pub request_id: String,
+    pub state: JobState,
+    /// Number of times JetStream has delivered the request
+    pub attempts: u64,
+    pub created_at: u64,
+    pub updated_at: u64,
+    /// Error message of the last failure, if any
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Every state change, oldest first
+    pub history: Vec<StateChange>,
+}
+
+/// Reads and writes job status records in a JetStream KV bucket
+pub struct StatusTracker {
+    kv: kv::Store,
+}
+
+impl StatusTracker {
+    /// Opens the status bucket, creating it if it does not exist yet
+    ///
+    /// Records are kept for `max_age`, after which lookups report the request as unknown.
+    pub async fn new(js: &jetstream::Context, bucket: &str, max_age: Duration) -> Result<Self> {
+        let kv = match js.get_key_value(bucket).await {
+            Ok(kv) => {
+                info!("Found existing status bucket: {}", bucket);
+                kv
+            }
+            Err(_) => {
+                info!("Creating status bucket: {}", bucket);
+                js.create_key_value(kv::Config {
+                    bucket: bucket.to_string(),
+                    description: "Meme request status".to_string(),
+                    max_age,
+                    history: 1,
+                    storage: jetstream::stream::StorageType::File,
+                    ..Default::default()
+                })
+                .await
+                .with_context(|| format!("Failed to create status bucket {}", bucket))?
+            }
+        };
+        Ok(Self { kv })
+    }
+
+    /// Returns the status record for `request_id`, if one exists
+    pub async fn get(&self, request_id: &str) -> Result<Option<JobStatus>> {
+        let Some(value) = self
+            .kv
+            .get(request_id)
+            .await
+            .with_context(|| format!("Failed to read status of {}", request_id))?
+        else {
+            return Ok(None);
+        };
+        let status = serde_json::from_slice(&value)
+            .with_context(|| format!("Invalid status record for {}", request_id))?;
+        Ok(Some(status))
+    }
+
+    /// Returns the status record for `request_id` with the revision to update it from
+    ///
+    /// The revision is 0 when there is no record, which only lets an update create one.
+    async fn entry(&self, request_id: &str) -> Result<(Option<JobStatus>, u64)> {
+        let Some(entry) = self
+            .kv
+            .entry(request_id)
+            .await
+            .with_context(|| format!("Failed to read status of {}", request_id))?
+        else {
+            return Ok((None, 0));
+        };
+        if entry.operation != kv::Operation::Put {
+            return Ok((None, entry.revision));
+        }
+        let status = serde_json::from_slice(&entry.value)
+            .with_context(|| format!("Invalid status record for {}", request_id))?;
+        Ok((Some(status), entry.revision))
+    }
+
+    /// Moves `request_id` to `state`, creating the record on first use
+    ///
+    /// `attempts` replaces the stored delivery count when given. `error` is recorded for
+    /// failures and cleared by any other state so that a retried request that later
+    /// succeeds does not keep showing the old error.
+    ///
+    /// `Cancelled` is final: a cancelled record is returned unchanged, so a task that raced
+    /// with the cancellation can tell from the result that it must not run.
+    pub async fn update(
+        &self,
+        request_id: &str,
+        state: JobState,
+        attempts: Option<u64>,
+        error: Option<String>,
+    ) -> Result<JobStatus> {
+        let (status, _) = self
+            .transition(request_id, state, attempts, error, |current| {
+                current != JobState::Cancelled
+            })
+            .await?;
+        Ok(status)
+    }
+
+    /// Marks `request_id` as cancelled unless it already finished
+    ///
+    /// Works whether the request is still waiting in the stream (with or without a record from
+    /// an earlier delivery) or is being processed. Returns false if the request had already
+    /// completed, failed or been cancelled.
+    pub async fn cancel(&self, request_id: &str) -> Result<bool> {
+        let (_, written) = self
+            .transition(request_id, JobState::Cancelled, None, None, |current| {
+                !current.is_final()
+            })
+            .await?;
+        Ok(written)
+    }
+
+    /// Applies a state change if `allowed` accepts the current state, and returns the
+    /// resulting record and whether it was written
+    ///
+    /// The write only succeeds if nobody changed the record since it was read; otherwise the
+    /// change is applied again on top of the newer record.
+    async fn transition(
+        &self,
+        request_id: &str,
+        state: JobState,
+        attempts: Option<u64>,
+        error: Option<String>,
+        allowed: impl Fn(JobState) -> bool,
+    ) -> Result<(JobStatus, bool)> {
+        for _ in 0..MAX_UPDATE_ATTEMPTS {
+            let now = now_secs();
+            let (existing, revision) = self.entry(request_id).await?;
+            if let Some(status) = existing.as_ref().filter(|status| !allowed(status.state)) {
+                debug!(request_id = %request_id, state = ?state, current = ?status.state, "Keeping job status");
+                return Ok((status.clone(), false));
+            }
+            let mut status = existing.unwrap_or_else(|| JobStatus {
+                request_id: request_id.to_string(),
+                state,
+                attempts: 0,
+                created_at: now,
+                updated_at: now,
+                error: None,
+                history: Vec::new(),
+            });
+
+            status.state = state;
+            status.updated_at = now;
+            status.error = error.clone();
+            if let Some(attempts) = attempts {
+                status.attempts = attempts;
+            }
+            status.history.push(StateChange {
+                state,
+                timestamp: now,
+            });
+
+            let value = serde_json::to_vec(&status)?;
+            match self
+                .kv
+                .update(request_id, Bytes::from(value), revision)
+                .await
+            {
+                Ok(_) => {
+                    debug!(request_id = %request_id, state = ?state, "Updated job status");
+                    return Ok((status, true));
+                }
+                Err(e) if e.kind() == kv::UpdateErrorKind::WrongLastRevision => {
+                    debug!(request_id = %request_id, state = ?state, "Status changed concurrently, retrying");
+                }
+                Err(e) => {
+                    return Err(e)
+                        .with_context(|| format!("Failed to write status of {}", request_id))
+                }
+            }
+        }
+        bail!(
+            "Status of {} kept changing concurrently, gave up after {} attempts",
+            request_id,
+            MAX_UPDATE_ATTEMPTS
+        )
+    }
+
+    /// Answers status lookups on `subject` until the subscription ends
+    ///
+    /// The request payload is the request ID as plain text. The reply is the JSON status
+    /// record, or an object with an `error` field if the request is unknown.
+    pub async fn serve(&self, client: async_nats::Client, subject: String) -> Result<()> {
+        let mut requests = client
+            .queue_subscribe(subject.clone(), STATUS_QUEUE_GROUP.to_string())
+            .await
+            .with_context(|| format!("Failed to subscribe to {}", subject))?;
+        info!("Answering status requests on {}", subject);
+
+        while let Some(message) = requests.next().await {
+            let Some(reply) = message.reply else {
+                continue;
+            };
+            let request_id = String::from_utf8_lossy(&message.payload).trim().to_string();
+
+            let response = match self.get(&request_id).await {
+                Ok(Some(status)) => serde_json::to_vec(&status)?,
+                Ok(None) => serde_json::to_vec(&serde_json::json!({
+                    "request_id": request_id,
+                    "error": "No status recorded for this request",
+                }))?,
+                Err(e) => {
+                    warn!(request_id = %request_id, "Status lookup failed: {:#}", e);
+                    serde_json::to_vec(&serde_json::json!({
+                        "request_id": request_id,
+                        "error": format!("Status lookup failed: {}", e),
+                    }))?
+                }
+            };
+
+            if let Err(e) = client.publish(reply, response.into()).await {
+                warn!(request_id = %request_id, "Failed to reply to status request: {}", e);
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub fn now_secs() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
@@ -0,0 +1,76 @@
+// ===== FILESYSTEM STORE =====
+// Writes images into a local directory, typically a volume that is also served by a web
+// server or CDN. Useful for single-node deployments and local development where running
+// an object store would be overkill.
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use std::path::PathBuf;
+use uuid::Uuid;
+
+use super::ImageStore;
+
+/// Image store backed by a directory on disk
+pub struct FilesystemStore {
+    root: PathBuf,
+    /// Base URL the directory is served from, e.g. `https://cdn.example.com/memes`
+    public_url: Option<String>,
+}
+
+impl FilesystemStore {
+    /// Creates the store, creating `root` if it does not exist yet
+    pub fn new(root: PathBuf, public_url: Option<String>) -> Result<Self> {
+        std::fs::create_dir_all(&root)
+            .with_context(|| format!("Failed to create storage directory {}", root.display()))?;
+        Ok(Self {
+            root,
+            public_url: public_url.map(|url| url.trim_end_matches('/').to_string()),
+        })
+    }
+}
+
+#[async_trait]
+impl ImageStore for FilesystemStore {
+    fn name(&self) -> &'static str {
+        "filesystem"
+    }
+
+    async fn put(&self, key: &str, bytes: Bytes, _content_type: &str) -> Result<()> {
+        // Keys are content hashes, so an existing file already holds these bytes
+        let path = self.root.join(key);
+        if tokio::fs::try_exists(&path).await.unwrap_or(false) {
+            return Ok(());
+        }
+
+        // Write to a temporary file first so readers never see a partially written image
+        // Each write gets its own temporary file, since concurrent writes of the same image
+        // would otherwise race on one path
+        let temp_path = self.root.join(format!(".{}.{}.tmp", key, Uuid::new_v4()));
+        if let Err(e) = tokio::fs::write(&temp_path, &bytes).await {
+            let _ = tokio::fs::remove_file(&temp_path).await;
+            return Err(e).with_context(|| format!("Failed to write {}", temp_path.display()));
+        }
+        if let Err(e) = tokio::fs::rename(&temp_path, &path).await {
+            let _ = tokio::fs::remove_file(&temp_path).await;
+            return Err(e)
+                .with_context(|| format!("Failed to move image into place at {}", path.display()));
+        }
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Bytes> {
+        let path = self.root.join(key);
+        let bytes = tokio::fs::read(&path)
+            .await
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        Ok(Bytes::from(bytes))
+    }
+
+    async fn url(&self, key: &str) -> Result<Option<String>> {
+        Ok(self
+            .public_url
+            .as_ref()
+            .map(|base| format!("{}/{}", base, key)))
+    }
+}
@@ -0,0 +1,147 @@
+// ===== IMAGE STORAGE =====
+// Generated images can be written to a blob store so that responses (and the Redis cache)
+// only carry a key and a URL instead of the full base64 payload, which keeps large PNGs under
+// the NATS `max_payload` limit and out of the stream. Stores live behind the `ImageStore`
+// trait, and `build_store` picks one at startup. Inline mode keeps the original behaviour of
+// embedding base64 image data in every response.
+
+use anyhow::{Context, Result};
+use async_nats::jetstream;
+use async_trait::async_trait;
+use bytes::Bytes;
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{io::Cursor, sync::Arc, time::Duration};
+
+use crate::Config;
+
+mod filesystem;
+mod nats;
+mod s3;
+
+pub use filesystem::FilesystemStore;
+pub use nats::NatsObjectStore;
+pub use s3::S3Store;
+
+/// Where generated images are delivered to clients
+///
+/// The value is chosen with `--image-storage` / `IMAGE_STORAGE`.
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ImageStorage {
+    /// Base64 image data embedded in every response (no storage needed)
+    Inline,
+    /// Files in a local or mounted directory
+    Filesystem,
+    /// S3-compatible object storage (AWS S3, MinIO, ...)
+    S3,
+    /// JetStream Object Store bucket on the NATS server the service already uses
+    Nats,
+}
+
+/// Format and size of an encoded image, sent to clients alongside the image reference
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImageInfo {
+    pub content_type: String,
+    pub width: u32,
+    pub height: u32,
+    pub size_bytes: usize,
+}
+
+impl ImageInfo {
+    /// Reads the format and dimensions from the image header without decoding the pixels
+    pub fn inspect(bytes: &[u8]) -> Result<Self> {
+        let reader = image::io::Reader::new(Cursor::new(bytes))
+            .with_guessed_format()
+            .context("Failed to read image header")?;
+        let format = reader.format().context("Unrecognized image format")?;
+        let (width, height) = reader
+            .into_dimensions()
+            .context("Failed to read image dimensions")?;
+        Ok(Self {
+            content_type: format.to_mime_type().to_string(),
+            width,
+            height,
+            size_bytes: bytes.len(),
+        })
+    }
+}
+
+/// An image that has been written to an `ImageStore`
+///
+/// This is what gets cached in Redis in storage mode, so a cache hit can be answered with
+/// the existing object instead of uploading the image again.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredImage {
+    pub key: String,
+    #[serde(flatten)]
+    pub info: ImageInfo,
+}
+
+/// Common interface for image stores
+#[async_trait]
+pub trait ImageStore: Send + Sync {
+    /// Short store name used in logs
+    fn name(&self) -> &'static str;
+
+    /// Bucket clients fetch objects from directly, for stores without URLs
+    fn bucket(&self) -> Option<&str> {
+        None
+    }
+
+    /// Writes `bytes` under `key`, overwriting any existing object
+    async fn put(&self, key: &str, bytes: Bytes, content_type: &str) -> Result<()>;
+
+    /// Reads the object stored under `key`
+    async fn get(&self, key: &str) -> Result<Bytes>;
+
+    /// URL clients can fetch the object from, if the store is reachable by clients
+    async fn url(&self, key: &str) -> Result<Option<String>>;
+}
+
+/// Writes an image to `store` under its content hash and returns the reference
+///
+/// Identical images (e.g. the same template with the same texts) map to the same key,
+/// so repeated uploads simply overwrite the object with identical bytes.
+pub async fn store_image(store: &dyn ImageStore, bytes: Bytes) -> Result<StoredImage> {
+    let info = ImageInfo::inspect(&bytes)?;
+    let key = content_key(&bytes, &info);
+    store
+        .put(&key, bytes, &info.content_type)
+        .await
+        .with_context(|| format!("Failed to store image {} in {} storage", key, store.name()))?;
+    Ok(StoredImage { key, info })
+}
+
+/// Object key for an image: the SHA-256 of its bytes plus the file extension
+fn content_key(bytes: &[u8], info: &ImageInfo) -> String {
+    let extension = match info.content_type.as_str() {
+        "image/jpeg" => "jpg",
+        "image/png" => "png",
+        _ => "bin",
+    };
+    format!("{}.{}", hex::encode(Sha256::digest(bytes)), extension)
+}
+
+/// Creates the image store selected in the configuration, or `None` for inline mode
+pub async fn build_store(
+    config: &Config,
+    js: &jetstream::Context,
+) -> Result<Option<Arc<dyn ImageStore>>> {
+    match config.image_storage {
+        ImageStorage::Inline => Ok(None),
+        ImageStorage::Filesystem => Ok(Some(Arc::new(FilesystemStore::new(
+            config.storage_dir.clone(),
+            config.storage_public_url.clone(),
+        )?))),
+        ImageStorage::S3 => Ok(Some(Arc::new(S3Store::new(config)?))),
+        ImageStorage::Nats => Ok(Some(Arc::new(
+            NatsObjectStore::new(
+                js,
+                &config.object_store_bucket,
+                Duration::from_secs(config.object_store_max_age_secs),
+            )
+            .await?,
+        ))),
+    }
+}
@@ -0,0 +1,104 @@
+// ===== NATS OBJECT STORE =====
+// Writes images to a JetStream Object Store bucket, reusing the NATS connection the service
+// already has instead of introducing another piece of infrastructure. Clients that talk NATS
+// (including the browser frontend over WebSocket) fetch the object directly from the bucket
+// using the key in the response, so no URL is generated.
+
+use anyhow::{Context, Result};
+use async_nats::{
+    jetstream::{self, object_store},
+    HeaderMap,
+};
+use async_trait::async_trait;
+use bytes::Bytes;
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tracing::info;
+
+use super::ImageStore;
+
+/// Image store backed by a JetStream Object Store bucket
+pub struct NatsObjectStore {
+    bucket: String,
+    store: object_store::ObjectStore,
+}
+
+impl NatsObjectStore {
+    /// Opens the bucket, creating it if it does not exist yet
+    ///
+    /// `max_age` bounds how long objects are kept; zero keeps them until deleted.
+    pub async fn new(js: &jetstream::Context, bucket: &str, max_age: Duration) -> Result<Self> {
+        let store = match js.get_object_store(bucket).await {
+            Ok(store) => {
+                info!("Found existing object store bucket: {}", bucket);
+                store
+            }
+            Err(_) => {
+                info!("Creating object store bucket: {}", bucket);
+                js.create_object_store(object_store::Config {
+                    bucket: bucket.to_string(),
+                    description: Some("Generated meme images".to_string()),
+                    max_age,
+                    storage: jetstream::stream::StorageType::File,
+                    ..Default::default()
+                })
+                .await
+                .with_context(|| format!("Failed to create object store bucket {}", bucket))?
+            }
+        };
+
+        Ok(Self {
+            bucket: bucket.to_string(),
+            store,
+        })
+    }
+}
+
+#[async_trait]
+impl ImageStore for NatsObjectStore {
+    fn name(&self) -> &'static str {
+        "nats"
+    }
+
+    fn bucket(&self) -> Option<&str> {
+        Some(&self.bucket)
+    }
+
+    async fn put(&self, key: &str, bytes: Bytes, content_type: &str) -> Result<()> {
+        // Keys are content hashes, so an existing object already holds these exact bytes
+        if self.store.info(key).await.is_ok() {
+            return Ok(());
+        }
+
+        let mut headers = HeaderMap::new();
+        headers.insert("Content-Type", content_type);
+        let metadata = object_store::ObjectMetadata {
+            name: key.to_string(),
+            headers: Some(headers),
+            ..Default::default()
+        };
+        self.store
+            .put(metadata, &mut bytes.as_ref())
+            .await
+            .with_context(|| format!("Failed to put {} in bucket {}", key, self.bucket))?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Bytes> {
+        let mut object = self
+            .store
+            .get(key)
+            .await
+            .with_context(|| format!("Failed to get {} from bucket {}", key, self.bucket))?;
+        let mut bytes = Vec::new();
+        object
+            .read_to_end(&mut bytes)
+            .await
+            .with_context(|| format!("Failed to read {} from bucket {}", key, self.bucket))?;
+        Ok(Bytes::from(bytes))
+    }
+
+    async fn url(&self, _key: &str) -> Result<Option<String>> {
+        Ok(None)
+    }
+}
@@ -0,0 +1,109 @@
+// ===== S3 STORE =====
+// Writes images to an S3-compatible bucket. Setting `S3_ENDPOINT` points the store at MinIO
+// or any other S3-compatible service instead of AWS. Clients get a public URL when
+// `STORAGE_PUBLIC_URL` is set (e.g. a CDN in front of the bucket), and a presigned GET URL
+// otherwise, so the bucket itself can stay private.
+
+use anyhow::{Context, Result};
+use async_trait::async_trait;
+use bytes::Bytes;
+use object_store::{
+    aws::{AmazonS3, AmazonS3Builder},
+    path::Path,
+    signer::Signer,
+    Attribute, Attributes, ObjectStore, PutOptions, PutPayload,
+};
+use std::time::Duration;
+
+use super::ImageStore;
+use crate::Config;
+
+/// Image store backed by an S3-compatible bucket
+pub struct S3Store {
+    store: AmazonS3,
+    /// Base URL the bucket is publicly served from; presigned URLs are used when unset
+    public_url: Option<String>,
+    /// Lifetime of presigned URLs
+    url_expiry: Duration,
+}
+
+impl S3Store {
+    /// Creates the store from the configuration
+    ///
+    /// Credentials are read from the standard `AWS_ACCESS_KEY_ID` / `AWS_SECRET_ACCESS_KEY`
+    /// environment variables (or the instance metadata service on AWS).
+    pub fn new(config: &Config) -> Result<Self> {
+        let bucket = config
+            .s3_bucket
+            .as_deref()
+            .context("S3_BUCKET is required when using s3 image storage")?;
+
+        let mut builder = AmazonS3Builder::from_env()
+            .with_bucket_name(bucket)
+            .with_region(&config.s3_region);
+        if let Some(endpoint) = &config.s3_endpoint {
+            // MinIO and most local deployments are plain HTTP with path-style addressing
+            builder = builder
+                .with_endpoint(endpoint)
+                .with_allow_http(endpoint.starts_with("http://"));
+        }
+
+        let store = builder
+            .build()
+            .with_context(|| format!("Failed to configure S3 bucket {}", bucket))?;
+
+        Ok(Self {
+            store,
+            public_url: config
+                .storage_public_url
+                .as_ref()
+                .map(|url| url.trim_end_matches('/').to_string()),
+            url_expiry: Duration::from_secs(config.s3_url_expiry_secs),
+        })
+    }
+}
+
+#[async_trait]
+impl ImageStore for S3Store {
+    fn name(&self) -> &'static str {
+        "s3"
+    }
+
+    async fn put(&self, key: &str, bytes: Bytes, content_type: &str) -> Result<()> {
+        let mut attributes = Attributes::new();
+        attributes.insert(Attribute::ContentType, content_type.to_string().into());
+        let options = PutOptions {
+            attributes,
+            ..Default::default()
+        };
+        self.store
+            .put_opts(&Path::from(key), PutPayload::from_bytes(bytes), options)
+            .await
+            .with_context(|| format!("Failed to upload {}", key))?;
+        Ok(())
+    }
+
+    async fn get(&self, key: &str) -> Result<Bytes> {
+        let object = self
+            .store
+            .get(&Path::from(key))
+            .await
+            .with_context(|| format!("Failed to download {}", key))?;
+        object
+            .bytes()
+            .await
+            .with_context(|| format!("Failed to read {}", key))
+    }
+
+    async fn url(&self, key: &str) -> Result<Option<String>> {
+        if let Some(base) = &self.public_url {
+            return Ok(Some(format!("{}/{}", base, key)));
+        }
+        let url = self
+            .store
+            .signed_url(http::Method::GET, &Path::from(key), self.url_expiry)
+            .await
+            .with_context(|| format!("Failed to presign URL for {}", key))?;
+        Ok(Some(url.to_string()))
+    }
+}
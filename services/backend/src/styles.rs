@@ -0,0 +1,124 @@
+// ===== STYLE PRESETS =====
+// The text sent to the image model wraps the user's prompt in a style template, e.g.
+// "Funny meme image with cartoon style, ... about: {prompt}. Do not include any text ...".
+// Templates are named presets that requests pick with their `style` field. The bundled
+// `styles.toml` is compiled in as the default set, and `STYLES_FILE` replaces it at startup,
+// so prompts can be tuned by editing a file (a ConfigMap in Kubernetes) instead of the code.
+
+use anyhow::{bail, Context, Result};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::{collections::BTreeMap, path::Path, sync::Arc};
+
+/// Styles used when no `STYLES_FILE` is configured
+const DEFAULT_STYLES: &str = include_str!("../styles.toml");
+
+/// Placeholder replaced with the user's prompt
+const PROMPT_PLACEHOLDER: &str = "{prompt}";
+
+/// On-disk format of a styles file
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct StylesFile {
+    /// Style used when a request does not name one
+    default: String,
+    styles: BTreeMap<String, Style>,
+}
+
+/// A named prompt template
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Style {
+    #[serde(skip)]
+    pub name: String,
+    /// Prompt sent to the model, with `{prompt}` standing for the user's prompt
+    template: String,
+    /// Short hash of the template, so edited templates don't hit images cached for the old one
+    #[serde(skip)]
+    fingerprint: String,
+}
+
+impl Style {
+    /// Full prompt for the image model
+    pub fn render(&self, prompt: &str) -> String {
+        self.template.replace(PROMPT_PLACEHOLDER, prompt)
+    }
+
+    /// Part of the image cache key
+    pub fn cache_key(&self) -> String {
+        format!("{}@{}", self.name, self.fingerprint)
+    }
+}
+
+/// All style presets, loaded once at startup
+pub struct StyleLibrary {
+    styles: BTreeMap<String, Arc<Style>>,
+    default: Arc<Style>,
+}
+
+impl StyleLibrary {
+    /// Loads the styles from `path`, or the bundled defaults when no path is given
+    pub fn load(path: Option<&Path>) -> Result<Self> {
+        match path {
+            Some(path) => {
+                let contents = std::fs::read_to_string(path)
+                    .with_context(|| format!("Failed to read styles {}", path.display()))?;
+                Self::parse(&contents)
+                    .with_context(|| format!("Invalid styles file {}", path.display()))
+            }
+            None => Self::parse(DEFAULT_STYLES).context("Invalid bundled styles"),
+        }
+    }
+
+    fn parse(contents: &str) -> Result<Self> {
+        let file: StylesFile = toml::from_str(contents)?;
+
+        let mut styles = BTreeMap::new();
+        for (name, mut style) in file.styles {
+            if !style.template.contains(PROMPT_PLACEHOLDER) {
+                bail!(
+                    "Style '{}' does not contain the {} placeholder",
+                    name,
+                    PROMPT_PLACEHOLDER
+                );
+            }
+            style.name = name.clone();
+            style.fingerprint = hex::encode(&Sha256::digest(style.template.as_bytes())[..4]);
+            styles.insert(name, Arc::new(style));
+        }
+
+        let default = styles
+            .get(&file.default)
+            .cloned()
+            .with_context(|| format!("Default style '{}' is not defined", file.default))?;
+        Ok(Self { styles, default })
+    }
+
+    /// Number of styles
+    pub fn len(&self) -> usize {
+        self.styles.len()
+    }
+
+    /// Name of the style used when a request does not name one
+    pub fn default_name(&self) -> &str {
+        &self.default.name
+    }
+
+    /// The style a request asked for, or the default one
+    pub fn get(&self, name: Option<&str>) -> Result<Arc<Style>> {
+        let Some(name) = name else {
+            return Ok(self.default.clone());
+        };
+        match self.styles.get(name) {
+            Some(style) => Ok(style.clone()),
+            None => {
+                let names: Vec<&str> = self.styles.keys().map(String::as_str).collect();
+                bail!(
+                    "Unknown style '{}', choose one of: {}",
+                    name,
+                    names.join(", ")
+                )
+            }
+        }
+    }
+}
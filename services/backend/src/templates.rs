@@ -0,0 +1,308 @@
+// ===== TEMPLATE LIBRARY =====
+// Classic meme templates (Drake, Distracted Boyfriend, Two Buttons, ...) are composed locally
+// from an image plus a set of text boxes, without calling any image provider. Each template
+// is described by a `<id>.json` metadata file in the templates directory that points at its
+// image and lists the box coordinates, maximum font size and rotation of every text box.
+// Everything is loaded and decoded once at startup.
+
+use anyhow::{Context, Result};
+use image::{Rgba, RgbaImage};
+use serde::Deserialize;
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tracing::{info, warn};
+
+use crate::caption::{encode_png, CaptionRenderer, TextBox, VerticalAlign};
+use crate::text;
+
+/// On-disk metadata for a single template
+#[derive(Debug, Deserialize)]
+struct TemplateMetadata {
+    /// Human readable name, e.g. "Drake Hotline Bling"
+    name: String,
+    /// Image file name, relative to the templates directory
+    image: PathBuf,
+    /// Text boxes in the order texts are supplied by clients
+    boxes: Vec<TemplateBoxMetadata>,
+}
+
+/// On-disk metadata for one text box, in template image pixels
+#[derive(Debug, Deserialize)]
+struct TemplateBoxMetadata {
+    /// Optional description shown to clients, e.g. "girlfriend"
+    #[serde(default)]
+    label: Option<String>,
+    x: u32,
+    y: u32,
+    width: u32,
+    height: u32,
+    #[serde(default = "default_max_font_size")]
+    max_font_size: f32,
+    /// Clockwise rotation in degrees around the box centre
+    #[serde(default)]
+    rotation: f32,
+    /// Text colour as `#rrggbb`
+    #[serde(default = "default_color")]
+    color: String,
+    /// Outline colour as `#rrggbb`, or `null` for plain text
+    #[serde(default = "default_outline")]
+    outline: Option<String>,
+}
+
+fn default_max_font_size() -> f32 {
+    64.0
+}
+
+fn default_color() -> String {
+    "#ffffff".to_string()
+}
+
+fn default_outline() -> Option<String> {
+    Some("#000000".to_string())
+}
+
+/// A text box ready for rendering
+#[derive(Debug, Clone)]
+struct TemplateBox {
+    text_box: TextBox,
+    rotation: f32,
+}
+
+/// A decoded template image with its text boxes
+pub struct MemeTemplate {
+    pub name: String,
+    image: RgbaImage,
+    boxes: Vec<TemplateBox>,
+}
+
+impl MemeTemplate {
+    /// Number of text boxes clients can fill in
+    pub fn box_count(&self) -> usize {
+        self.boxes.len()
+    }
+
+    /// Draws `texts` into the template's boxes, in order, and returns the PNG bytes
+    ///
+    /// Supplying fewer texts than boxes leaves the remaining boxes empty.
+    pub fn render(&self, renderer: &CaptionRenderer, texts: &[String]) -> Result<Vec<u8>> {
+        if texts.len() > self.boxes.len() {
+            return Err(anyhow::anyhow!(
+                "Template '{}' has {} text boxes but {} texts were given",
+                self.name,
+                self.boxes.len(),
+                texts.len()
+            ));
+        }
+
+        let mut image = self.image.clone();
+        for (template_box, text) in self.boxes.iter().zip(texts) {
+            if template_box.rotation.abs() < f32::EPSILON {
+                renderer.draw_text_box(&mut image, text, &template_box.text_box);
+                continue;
+            }
+
+            // Rotated boxes are drawn upright on a transparent layer, then rotated into place
+            let text_box = &template_box.text_box;
+            let mut layer = RgbaImage::new(text_box.width as u32, text_box.height as u32);
+            let upright = TextBox {
+                x: 0.0,
+                y: 0.0,
+                ..text_box.clone()
+            };
+            renderer.draw_text_box(&mut layer, text, &upright);
+            composite_rotated(
+                &mut image,
+                &layer,
+                text_box.x + text_box.width / 2.0,
+                text_box.y + text_box.height / 2.0,
+                template_box.rotation,
+            );
+        }
+
+        encode_png(&image)
+    }
+}
+
+/// All templates found in the templates directory, keyed by id (the metadata file stem)
+#[derive(Default)]
+pub struct TemplateLibrary {
+    templates: HashMap<String, Arc<MemeTemplate>>,
+}
+
+impl TemplateLibrary {
+    /// Loads every `*.json` template in `dir`
+    ///
+    /// A missing directory yields an empty library so that deployments without templates
+    /// still start. Individual templates that fail to load are skipped with a warning.
+    pub fn load(dir: &Path) -> Result<Self> {
+        let mut library = Self::default();
+        if !dir.is_dir() {
+            warn!("Templates directory {} not found, template memes are disabled", dir.display());
+            return Ok(library);
+        }
+
+        let entries = std::fs::read_dir(dir)
+            .with_context(|| format!("Failed to read templates directory {}", dir.display()))?;
+        for entry in entries {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+                continue;
+            }
+            let Some(id) = path.file_stem().and_then(|stem| stem.to_str()) else {
+                continue;
+            };
+            match load_template(dir, &path) {
+                Ok(template) => {
+                    info!(template = %id, boxes = template.box_count(), "Loaded meme template");
+                    library
+                        .templates
+                        .insert(id.to_lowercase(), Arc::new(template));
+                }
+                Err(e) => warn!(template = %id, "Skipping template: {:#}", e),
+            }
+        }
+
+        Ok(library)
+    }
+
+    /// Looks up a template by id, case-insensitively
+    pub fn get(&self, id: &str) -> Option<Arc<MemeTemplate>> {
+        self.templates.get(&id.to_lowercase()).cloned()
+    }
+
+    pub fn len(&self) -> usize {
+        self.templates.len()
+    }
+}
+
+/// Reads one metadata file and decodes the image it references
+fn load_template(dir: &Path, metadata_path: &Path) -> Result<MemeTemplate> {
+    let metadata: TemplateMetadata = serde_json::from_slice(
+        &std::fs::read(metadata_path)
+            .with_context(|| format!("Failed to read {}", metadata_path.display()))?,
+    )
+    .with_context(|| format!("Invalid template metadata in {}", metadata_path.display()))?;
+
+    let image_path = dir.join(&metadata.image);
+    let image = image::open(&image_path)
+        .with_context(|| format!("Failed to load template image {}", image_path.display()))?
+        .to_rgba8();
+
+    let boxes = metadata
+        .boxes
+        .into_iter()
+        .map(|b| {
+            // Checked so that hostile metadata cannot overflow its way past the bounds check
+            let fits = |start: u32, len: u32, limit: u32| {
+                start.checked_add(len).is_some_and(|end| end <= limit)
+            };
+            if !fits(b.x, b.width, image.width()) || !fits(b.y, b.height, image.height()) {
+                return Err(anyhow::anyhow!(
+                    "Text box {} lies outside the {}x{} image",
+                    b.label.as_deref().unwrap_or("(unlabelled)"),
+                    image.width(),
+                    image.height()
+                ));
+            }
+            Ok(TemplateBox {
+                text_box: TextBox {
+                    x: b.x as f32,
+                    y: b.y as f32,
+                    width: b.width as f32,
+                    height: b.height as f32,
+                    max_font_size: b.max_font_size,
+                    align: VerticalAlign::Middle,
+                    fill: parse_color(&b.color)?,
+                    outline: b.outline.as_deref().map(parse_color).transpose()?,
+                },
+                rotation: b.rotation,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(MemeTemplate {
+        name: metadata.name,
+        image,
+        boxes,
+    })
+}
+
+/// Parses a `#rrggbb` colour string
+fn parse_color(value: &str) -> Result<Rgba<u8>> {
+    let hex = value.trim_start_matches('#');
+    let channel = |i: usize| {
+        hex.get(i..i + 2)
+            .and_then(|c| u8::from_str_radix(c, 16).ok())
+            .with_context(|| format!("Invalid colour '{}', expected #rrggbb", value))
+    };
+    if hex.len() != 6 {
+        return Err(anyhow::anyhow!("Invalid colour '{}', expected #rrggbb", value));
+    }
+    Ok(Rgba([channel(0)?, channel(2)?, channel(4)?, 255]))
+}
+
+/// Composites `layer` onto `image`, rotated clockwise by `degrees` around (`cx`, `cy`)
+///
+/// Each destination pixel is mapped back into the layer and sampled bilinearly with
+/// premultiplied alpha, so rotated text keeps smooth edges without dark fringes.
+fn composite_rotated(image: &mut RgbaImage, layer: &RgbaImage, cx: f32, cy: f32, degrees: f32) {
+    let (sin, cos) = degrees.to_radians().sin_cos();
+    let (half_w, half_h) = (layer.width() as f32 / 2.0, layer.height() as f32 / 2.0);
+    let extent_x = half_w * cos.abs() + half_h * sin.abs();
+    let extent_y = half_w * sin.abs() + half_h * cos.abs();
+
+    let x0 = (cx - extent_x).floor().max(0.0) as u32;
+    let y0 = (cy - extent_y).floor().max(0.0) as u32;
+    let x1 = ((cx + extent_x).ceil() as u32).min(image.width());
+    let y1 = ((cy + extent_y).ceil() as u32).min(image.height());
+
+    for py in y0..y1 {
+        for px in x0..x1 {
+            let (dx, dy) = (px as f32 + 0.5 - cx, py as f32 + 0.5 - cy);
+            let lx = dx * cos + dy * sin + half_w - 0.5;
+            let ly = -dx * sin + dy * cos + half_h - 0.5;
+            if let Some(color) = sample_bilinear(layer, lx, ly) {
+                text::blend_pixel(image.get_pixel_mut(px, py), color, 1.0);
+            }
+        }
+    }
+}
+
+/// Bilinear sample at fractional coordinates; pixels outside the layer are transparent
+fn sample_bilinear(layer: &RgbaImage, x: f32, y: f32) -> Option<Rgba<u8>> {
+    let (fx, fy) = (x.floor(), y.floor());
+    let (tx, ty) = (x - fx, y - fy);
+    let mut premultiplied = [0.0f32; 3];
+    let mut alpha = 0.0f32;
+
+    for (ox, oy, weight) in [
+        (0, 0, (1.0 - tx) * (1.0 - ty)),
+        (1, 0, tx * (1.0 - ty)),
+        (0, 1, (1.0 - tx) * ty),
+        (1, 1, tx * ty),
+    ] {
+        let (sx, sy) = (fx as i64 + ox, fy as i64 + oy);
+        if sx < 0 || sy < 0 || sx >= layer.width() as i64 || sy >= layer.height() as i64 {
+            continue;
+        }
+        let pixel = layer.get_pixel(sx as u32, sy as u32);
+        let a = pixel[3] as f32 / 255.0 * weight;
+        for channel in 0..3 {
+            premultiplied[channel] += pixel[channel] as f32 * a;
+        }
+        alpha += a;
+    }
+
+    if alpha <= 0.0 {
+        return None;
+    }
+    Some(Rgba([
+        (premultiplied[0] / alpha).round() as u8,
+        (premultiplied[1] / alpha).round() as u8,
+        (premultiplied[2] / alpha).round() as u8,
+        (alpha * 255.0).round() as u8,
+    ]))
+}
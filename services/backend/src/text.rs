@@ -0,0 +1,219 @@
+// ===== TEXT RENDERING =====
+// Minimal text layout on top of ab_glyph: measuring, greedy word wrapping and alpha-blended
+// glyph drawing into an RGBA buffer. A bold Noto Sans face is compiled into the binary so that
+// text rendering works in any container without installing system fonts.
+
+use ab_glyph::{point, Font, FontRef, PxScale, ScaleFont};
+use image::{Rgba, RgbaImage};
+use std::sync::OnceLock;
+
+/// Returns the bundled bold font, parsing it on first use
+pub fn default_font() -> &'static FontRef<'static> {
+    static FONT: OnceLock<FontRef<'static>> = OnceLock::new();
+    FONT.get_or_init(|| {
+        FontRef::try_from_slice(notosans::BOLD_TTF).expect("bundled Noto Sans font is valid")
+    })
+}
+
+/// Distance between the baselines of two consecutive lines
+pub fn line_height(font: &impl Font, scale: PxScale) -> f32 {
+    let scaled = font.as_scaled(scale);
+    scaled.height() + scaled.line_gap()
+}
+
+/// Horizontal advance of a single line of text, including kerning
+pub fn text_width(font: &impl Font, scale: PxScale, text: &str) -> f32 {
+    let scaled = font.as_scaled(scale);
+    let mut width = 0.0;
+    let mut previous = None;
+    for c in text.chars() {
+        let id = scaled.glyph_id(c);
+        if let Some(prev) = previous {
+            width += scaled.kern(prev, id);
+        }
+        width += scaled.h_advance(id);
+        previous = Some(id);
+    }
+    width
+}
+
+/// Greedy word wrap that keeps every line within `max_width` where possible
+///
+/// A single word wider than `max_width` is kept on its own line rather than being split,
+/// so callers that need a hard fit should shrink the scale and wrap again.
+pub fn wrap_text(font: &impl Font, scale: PxScale, text: &str, max_width: f32) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut current = String::new();
+    for word in text.split_whitespace() {
+        let candidate = if current.is_empty() {
+            word.to_string()
+        } else {
+            format!("{} {}", current, word)
+        };
+        if current.is_empty() || text_width(font, scale, &candidate) <= max_width {
+            current = candidate;
+        } else {
+            lines.push(std::mem::replace(&mut current, word.to_string()));
+        }
+    }
+    if !current.is_empty() {
+        lines.push(current);
+    }
+    lines
+}
+
+/// Draws a single line of text with its top edge at `y`
+///
+/// Coverage from the rasterizer is used as alpha, so edges are anti-aliased against whatever
+/// is already in the image. Pixels falling outside the image are clipped.
+pub fn draw_text(
+    image: &mut RgbaImage,
+    font: &impl Font,
+    scale: PxScale,
+    x: f32,
+    y: f32,
+    text: &str,
+    color: Rgba<u8>,
+) {
+    let scaled = font.as_scaled(scale);
+    let baseline = y + scaled.ascent();
+    let mut caret = x;
+    let mut previous = None;
+
+    for c in text.chars() {
+        let id = scaled.glyph_id(c);
+        if let Some(prev) = previous {
+            caret += scaled.kern(prev, id);
+        }
+        let glyph = id.with_scale_and_position(scale, point(caret, baseline));
+        caret += scaled.h_advance(id);
+        previous = Some(id);
+
+        let Some(outlined) = font.outline_glyph(glyph) else {
+            continue;
+        };
+        let bounds = outlined.px_bounds();
+        outlined.draw(|gx, gy, coverage| {
+            let px = bounds.min.x as i32 + gx as i32;
+            let py = bounds.min.y as i32 + gy as i32;
+            if px >= 0 && py >= 0 && (px as u32) < image.width() && (py as u32) < image.height() {
+                blend_pixel(image.get_pixel_mut(px as u32, py as u32), color, coverage);
+            }
+        });
+    }
+}
+
+/// Alpha-blends `color` onto `pixel` with the given coverage in `0.0..=1.0`
+///
+/// Uses the standard "over" operator so that drawing onto a transparent layer and
+/// compositing that layer later gives the same result as drawing onto the image directly.
+pub fn blend_pixel(pixel: &mut Rgba<u8>, color: Rgba<u8>, coverage: f32) {
+    let alpha = coverage.clamp(0.0, 1.0) * (color[3] as f32 / 255.0);
+    if alpha <= 0.0 {
+        return;
+    }
+    let dst_alpha = pixel[3] as f32 / 255.0;
+    let out_alpha = alpha + dst_alpha * (1.0 - alpha);
+    for channel in 0..3 {
+        let blended = (color[channel] as f32 * alpha
+            + pixel[channel] as f32 * dst_alpha * (1.0 - alpha))
+            / out_alpha;
+        pixel[channel] = blended.round() as u8;
+    }
+    pixel[3] = (out_alpha * 255.0).round() as u8;
+}
+
+/// Draws a single line of text with a solid outline of `outline_width` pixels
+///
+/// The glyph coverage is rasterized into a mask once, then dilated with a circular kernel to
+/// produce the outline. This gives an even stroke around every glyph regardless of size,
+/// unlike repeatedly drawing the text at offsets.
+#[allow(clippy::too_many_arguments)]
+pub fn draw_outlined_text(
+    image: &mut RgbaImage,
+    font: &impl Font,
+    scale: PxScale,
+    x: f32,
+    y: f32,
+    text: &str,
+    fill: Rgba<u8>,
+    outline: Rgba<u8>,
+    outline_width: u32,
+) {
+    let scaled = font.as_scaled(scale);
+    let pad = outline_width as i32;
+    let mask_width = text_width(font, scale, text).ceil() as i32 + 2 * pad + 2;
+    let mask_height = scaled.height().ceil() as i32 + 2 * pad + 2;
+    if mask_width <= 0 || mask_height <= 0 {
+        return;
+    }
+
+    // Rasterize the fill coverage relative to the mask origin at (x - pad, y - pad)
+    let mut mask = vec![0.0f32; (mask_width * mask_height) as usize];
+    let baseline = pad as f32 + scaled.ascent();
+    let mut caret = pad as f32;
+    let mut previous = None;
+    for c in text.chars() {
+        let id = scaled.glyph_id(c);
+        if let Some(prev) = previous {
+            caret += scaled.kern(prev, id);
+        }
+        let glyph = id.with_scale_and_position(scale, point(caret, baseline));
+        caret += scaled.h_advance(id);
+        previous = Some(id);
+
+        let Some(outlined) = font.outline_glyph(glyph) else {
+            continue;
+        };
+        let bounds = outlined.px_bounds();
+        outlined.draw(|gx, gy, coverage| {
+            let mx = bounds.min.x as i32 + gx as i32;
+            let my = bounds.min.y as i32 + gy as i32;
+            if mx >= 0 && my >= 0 && mx < mask_width && my < mask_height {
+                let cell = &mut mask[(my * mask_width + mx) as usize];
+                *cell = cell.max(coverage);
+            }
+        });
+    }
+
+    // Offsets of the circular dilation kernel
+    let kernel: Vec<(i32, i32)> = (-pad..=pad)
+        .flat_map(|dy| (-pad..=pad).map(move |dx| (dx, dy)))
+        .filter(|(dx, dy)| dx * dx + dy * dy <= pad * pad)
+        .collect();
+
+    let origin_x = x.round() as i32 - pad;
+    let origin_y = y.round() as i32 - pad;
+    for my in 0..mask_height {
+        let py = origin_y + my;
+        if py < 0 || py >= image.height() as i32 {
+            continue;
+        }
+        for mx in 0..mask_width {
+            let px = origin_x + mx;
+            if px < 0 || px >= image.width() as i32 {
+                continue;
+            }
+
+            let mut stroke = 0.0f32;
+            for (dx, dy) in &kernel {
+                let (sx, sy) = (mx + dx, my + dy);
+                if sx >= 0 && sy >= 0 && sx < mask_width && sy < mask_height {
+                    stroke = stroke.max(mask[(sy * mask_width + sx) as usize]);
+                    if stroke >= 1.0 {
+                        break;
+                    }
+                }
+            }
+
+            let pixel = image.get_pixel_mut(px as u32, py as u32);
+            if stroke > 0.0 {
+                blend_pixel(pixel, outline, stroke);
+            }
+            let coverage = mask[(my * mask_width + mx) as usize];
+            if coverage > 0.0 {
+                blend_pixel(pixel, fill, coverage);
+            }
+        }
+    }
+}
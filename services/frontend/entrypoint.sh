@@ -5,6 +5,7 @@ cat > /usr/share/nginx/html/config.js << EOF
 window.RUNTIME_CONFIG = {
   NATS_URL: "${VITE_NATS_URL}",
   REQUEST_SUBJECT: "${VITE_REQUEST_SUBJECT}",
+  FAST_REQUEST_SUBJECT: "${VITE_FAST_REQUEST_SUBJECT}",
   RESPONSE_SUBJECT: "${VITE_RESPONSE_SUBJECT}"
 };
 EOF
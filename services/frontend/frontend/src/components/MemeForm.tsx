@@ -10,22 +10,30 @@ import {
   Typography,
   CircularProgress,
   Tooltip,
-  Paper
+  Paper,
+  MenuItem
 } from '@mui/material';
-import { FlashOn, AspectRatio, Send, Help } from '@mui/icons-material';
+import { FlashOn, AspectRatio, Crop, Palette, Send, Help } from '@mui/icons-material';
 import { useMeme } from '../context/MemeContext';
 
 const MemeForm = () => {
-  const { generateMeme, loading } = useMeme();
+  const { generateMeme, cancelMeme, loading } = useMeme();
   const [prompt, setPrompt] = useState('');
   const [fastMode, setFastMode] = useState(true);
   const [smallImage, setSmallImage] = useState(true);
+  const [aspectRatio, setAspectRatio] = useState('1:1');
+  const [style, setStyle] = useState('cartoon');
+  const [topText, setTopText] = useState('');
+  const [bottomText, setBottomText] = useState('');
 
   const handleSubmit = async (e: React.FormEvent) => {
     e.preventDefault();
     if (!prompt.trim()) return;
     
-    await generateMeme(prompt.trim(), fastMode, smallImage);
+    await generateMeme(prompt.trim(), fastMode, smallImage, {
+      topText: topText.trim(),
+      bottomText: bottomText.trim()
+    }, aspectRatio, style);
   };
 
   return (
@@ -63,6 +71,31 @@ const MemeForm = () => {
             sx: { borderRadius: 2, fontSize: { xs: '0.9rem', sm: '1rem' } }
           }}
         />
+
+        <Box sx={{ display: 'flex', flexDirection: { xs: 'column', sm: 'row' }, gap: 2 }}>
+          <TextField
+            fullWidth
+            label="Top text"
+            variant="outlined"
+            value={topText}
+            onChange={(e) => setTopText(e.target.value)}
+            placeholder="e.g., when the code compiles"
+            disabled={loading}
+            inputProps={{ maxLength: 200 }}
+            InputProps={{ sx: { borderRadius: 2 } }}
+          />
+          <TextField
+            fullWidth
+            label="Bottom text"
+            variant="outlined"
+            value={bottomText}
+            onChange={(e) => setBottomText(e.target.value)}
+            placeholder="e.g., on the first try"
+            disabled={loading}
+            inputProps={{ maxLength: 200 }}
+            InputProps={{ sx: { borderRadius: 2 } }}
+          />
+        </Box>
         
         <Box sx={{ mt: 3 }}>
           <Box sx={{ 
@@ -90,7 +123,7 @@ const MemeForm = () => {
               />
             </Tooltip>
             
-            <Tooltip title="Generate a smaller 512x512 image instead of 1024x1024">
+            <Tooltip title="Generate a half-size image, e.g. 512x512 instead of 1024x1024">
               <FormControlLabel
                 control={
                   <Switch 
@@ -108,6 +141,50 @@ const MemeForm = () => {
                 disabled={loading}
               />
             </Tooltip>
+
+            <TextField
+              select
+              size="small"
+              label={
+                <Box sx={{ display: 'flex', alignItems: 'center' }}>
+                  <Crop fontSize="small" sx={{ mr: 0.5 }} />
+                  Aspect ratio
+                </Box>
+              }
+              value={aspectRatio}
+              onChange={(e) => setAspectRatio(e.target.value)}
+              disabled={loading}
+              sx={{ minWidth: 160 }}
+              InputProps={{ sx: { borderRadius: 2 } }}
+            >
+              <MenuItem value="1:1">Square (1:1)</MenuItem>
+              <MenuItem value="4:3">Landscape (4:3)</MenuItem>
+              <MenuItem value="3:4">Portrait (3:4)</MenuItem>
+              <MenuItem value="16:9">Wide (16:9)</MenuItem>
+              <MenuItem value="9:16">Story (9:16)</MenuItem>
+            </TextField>
+
+            <TextField
+              select
+              size="small"
+              label={
+                <Box sx={{ display: 'flex', alignItems: 'center' }}>
+                  <Palette fontSize="small" sx={{ mr: 0.5 }} />
+                  Style
+                </Box>
+              }
+              value={style}
+              onChange={(e) => setStyle(e.target.value)}
+              disabled={loading}
+              sx={{ minWidth: 160 }}
+              InputProps={{ sx: { borderRadius: 2 } }}
+            >
+              <MenuItem value="cartoon">Cartoon</MenuItem>
+              <MenuItem value="photorealistic">Photo-realistic</MenuItem>
+              <MenuItem value="pixel-art">Pixel art</MenuItem>
+              <MenuItem value="anime">Anime</MenuItem>
+              <MenuItem value="deep-fried">Deep-fried</MenuItem>
+            </TextField>
           </Box>
           
           <Box sx={{ display: 'flex', justifyContent: 'center', width: '100%', mt: 2 }}>
@@ -141,6 +218,9 @@ const MemeForm = () => {
             <Typography variant="body2" color="textSecondary">
               This may take 15-30 seconds. Please wait.
             </Typography>
+            <Button variant="outlined" color="secondary" onClick={cancelMeme} sx={{ mt: 2, borderRadius: 2 }}>
+              Cancel
+            </Button>
           </CardContent>
         </Card>
       )}
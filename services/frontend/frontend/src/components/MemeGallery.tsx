@@ -12,14 +12,15 @@ import {
 } from '@mui/material';
 import { Download, ClearAll } from '@mui/icons-material';
 import { useMeme } from '../context/MemeContext';
+import { memeImageSrc } from '../services/NatsService';
 
 const MemeGallery = () => {
   const { memes, clearGallery } = useMeme();
 
   // Function to download a meme image
-  const downloadMeme = (base64Data: string, prompt: string) => {
+  const downloadMeme = (src: string, prompt: string) => {
     const link = document.createElement('a');
-    link.href = `data:image/png;base64,${base64Data}`;
+    link.href = src;
     link.download = `meme-${prompt.substring(0, 20).replace(/\s+/g, '-')}-${Date.now()}.png`;
     document.body.appendChild(link);
     link.click();
@@ -85,7 +86,7 @@ const MemeGallery = () => {
             }}>
               <CardMedia
                 component="img"
-                image={`data:image/png;base64,${meme.image_data}`}
+                image={memeImageSrc(meme)}
                 alt={meme.prompt}
                 sx={{ 
                   aspectRatio: '1/1',
@@ -126,7 +127,7 @@ const MemeGallery = () => {
                 <Box sx={{ display: 'flex', justifyContent: 'flex-end', mt: 1 }}>
                   <Tooltip title="Download meme">
                     <IconButton 
-                      onClick={() => downloadMeme(meme.image_data, meme.prompt)}
+                      onClick={() => downloadMeme(memeImageSrc(meme), meme.prompt)}
                       size="small"
                     >
                       <Download />
@@ -1,12 +1,13 @@
 import { createContext, useContext, useState, ReactNode, useEffect } from 'react';
-import NatsService, { MemeResponse } from '../services/NatsService';
+import NatsService, { MemeCaptions, MemeResponse } from '../services/NatsService';
 
 interface MemeContextType {
   memes: MemeResponse[];
   loading: boolean;
   currentRequestId: string | null;
   error: string | null;
-  generateMeme: (prompt: string, fastMode: boolean, smallImage: boolean) => Promise<void>;
+  generateMeme: (prompt: string, fastMode: boolean, smallImage: boolean, captions?: MemeCaptions, aspectRatio?: string, style?: string) => Promise<void>;
+  cancelMeme: () => void;
   clearError: () => void;
   clearGallery: () => void;
 }
@@ -77,7 +78,7 @@ export function MemeProvider({ children }: { children: ReactNode }) {
     };
   }, []);
 
-  const generateMeme = async (prompt: string, fastMode: boolean, smallImage: boolean) => {
+  const generateMeme = async (prompt: string, fastMode: boolean, smallImage: boolean, captions: MemeCaptions = {}, aspectRatio: string = '1:1', style?: string) => {
     try {
       // Check if NATS is connected first
       if (!NatsService.isConnected()) {
@@ -94,6 +95,9 @@ export function MemeProvider({ children }: { children: ReactNode }) {
       console.log(`Generating meme with prompt: "${prompt}"`, {
         fastMode,
         smallImage,
+        aspectRatio,
+        style,
+        captions,
         timestamp: new Date().toISOString()
       });
 
@@ -114,7 +118,10 @@ export function MemeProvider({ children }: { children: ReactNode }) {
           setError(errorResponse.error);
           setLoading(false);
           setCurrentRequestId(null);
-        }
+        },
+        captions,
+        aspectRatio,
+        style
       );
 
       setCurrentRequestId(requestId);
@@ -127,6 +134,14 @@ export function MemeProvider({ children }: { children: ReactNode }) {
     }
   };
 
+  const cancelMeme = () => {
+    if (currentRequestId) {
+      NatsService.cancelMeme(currentRequestId);
+    }
+    setLoading(false);
+    setCurrentRequestId(null);
+  };
+
   const clearError = () => setError(null);
   
   const clearGallery = () => setMemes([]);
@@ -137,6 +152,7 @@ export function MemeProvider({ children }: { children: ReactNode }) {
     currentRequestId,
     error,
     generateMeme,
+    cancelMeme,
     clearError,
     clearGallery
   };
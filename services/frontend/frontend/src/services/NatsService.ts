@@ -7,18 +7,85 @@ export interface MemeRequest {
   prompt: string;
   fast_mode: boolean;
   small_image: boolean;
+  // Size preset such as '16:9'; halved when small_image is set
+  aspect_ratio?: string;
+  // Style preset the prompt is wrapped in, e.g. 'pixel-art'; the service default when unset
+  style?: string;
+  // Sampling settings; the response echoes the values used so an image can be reproduced
+  seed?: number;
+  num_inference_steps?: number;
+  guidance_scale?: number;
+  negative_prompt?: string;
+  top_text?: string;
+  bottom_text?: string;
+  reply_to?: string;
+  session_id?: string;
+  // Name of a selectable model from the backend's model registry
+  model?: string;
+}
+
+export interface MemeCaptions {
+  topText?: string;
+  bottomText?: string;
 }
 
 export interface MemeResponse {
   request_id: string;
-  image_data: string;
+  // Base64 image, only sent when the backend delivers images inline
+  image_data?: string;
+  // Reference to the stored image when the backend uses an image store
+  image_key?: string;
+  image_url?: string;
+  // JetStream Object Store bucket holding image_key, fetched directly over NATS
+  image_bucket?: string;
+  content_type: string;
+  width: number;
+  height: number;
+  size_bytes: number;
+  // Model that generated the image; absent for template memes
+  model?: string;
+  // Sampling settings the image was generated with, as far as the provider supports them
+  seed?: number;
+  num_inference_steps?: number;
+  guidance_scale?: number;
+  negative_prompt?: string;
   prompt: string;
   timestamp: number;
 }
 
+const SESSION_STORAGE_KEY = 'meme-session-id';
+
+/**
+ * Returns this tab's session ID, creating one on first use
+ */
+function loadSessionId(): string {
+  try {
+    const existing = window.sessionStorage.getItem(SESSION_STORAGE_KEY);
+    if (existing) {
+      return existing;
+    }
+    const sessionId = uuidv4();
+    window.sessionStorage.setItem(SESSION_STORAGE_KEY, sessionId);
+    return sessionId;
+  } catch {
+    // Storage can be unavailable (e.g. privacy mode); fall back to a per-page ID
+    return uuidv4();
+  }
+}
+
+/**
+ * Source URL for displaying or downloading a meme, whether it was stored or sent inline
+ */
+export const memeImageSrc = (meme: MemeResponse): string =>
+  meme.image_url || `data:${meme.content_type || 'image/png'};base64,${meme.image_data}`;
+
 export interface MemeError {
   request_id: string;
   error: string;
+  // Every code but 'failed' means the request was rejected and can be retried later
+  code?: 'failed' | 'rate_limited' | 'quota_exceeded' | 'unavailable';
+  // Seconds to wait before retrying a rejected request
+  retry_after?: number;
   timestamp: number;
 }
 
@@ -30,9 +97,17 @@ class NatsService {
   private responseCallbacks: Map<string, (response: MemeResponse) => void> = new Map();
   private errorCallbacks: Map<string, (error: MemeError) => void> = new Map();
 
+  // Per-client token so the backend only sends us our own responses
+  private clientId: string = uuidv4();
+
+  // Per-tab session, kept across reloads, so the backend can share capacity fairly between users
+  private sessionId: string = loadSessionId();
+
   // Configuration properties from runtime config
   private serverUrl: string;
   private requestSubject: string;
+  private fastRequestSubject: string;
+  private cancelSubject: string;
   private responseSubject: string;
   private errorSubject: string;
 
@@ -53,13 +128,19 @@ class NatsService {
     }
 
     this.requestSubject = config.REQUEST_SUBJECT || 'meme.request';
-    this.responseSubject = config.RESPONSE_SUBJECT || 'meme.response';
+    // Fast-mode requests can use a higher-priority lane, e.g. 'meme.request.high'
+    this.fastRequestSubject = config.FAST_REQUEST_SUBJECT || this.requestSubject;
+    this.cancelSubject = config.CANCEL_SUBJECT || 'meme.cancel';
+    // Responses are published on `<response subject>.<reply_to>`, so listen on our own token
+    const sharedResponseSubject = config.RESPONSE_SUBJECT || 'meme.response';
+    this.responseSubject = `${sharedResponseSubject}.${this.clientId}`;
     this.errorSubject = `${this.responseSubject}.error`;
 
     console.log('🔌 Connecting to NATS server at ' + this.serverUrl + '...');
     console.log('💻 Environment details: ', {
       url: this.serverUrl,
       requestSubject: this.requestSubject,
+      fastRequestSubject: this.fastRequestSubject,
       responseSubject: this.responseSubject,
       errorSubject: this.errorSubject
     });
@@ -217,6 +298,7 @@ class NatsService {
           const callback = this.responseCallbacks.get(response.request_id);
 
           if (callback) {
+            await this.resolveObjectStoreImage(response);
             callback(response);
             this.responseCallbacks.delete(response.request_id);
             this.errorCallbacks.delete(response.request_id); // Clean up error callback too
@@ -228,6 +310,22 @@ class NatsService {
     })();
   }
 
+  /**
+   * Downloads images stored in a JetStream Object Store bucket and exposes them via an object URL
+   */
+  private async resolveObjectStoreImage(response: MemeResponse): Promise<void> {
+    if (!this.connection || response.image_url || response.image_data) return;
+    if (!response.image_bucket || !response.image_key) return;
+
+    const bucket = await this.connection.jetstream().views.os(response.image_bucket);
+    const data = await bucket.getBlob(response.image_key);
+    if (!data) {
+      throw new Error(`Image ${response.image_key} not found in bucket ${response.image_bucket}`);
+    }
+    const blob = new Blob([data], { type: response.content_type || 'image/png' });
+    response.image_url = URL.createObjectURL(blob);
+  }
+
   private processErrors(): void {
     if (!this.errorSubscription) return;
 
@@ -254,7 +352,10 @@ class NatsService {
     fastMode: boolean = false,
     smallImage: boolean = false,
     onResponse: (response: MemeResponse) => void,
-    onError: (error: MemeError) => void
+    onError: (error: MemeError) => void,
+    captions: MemeCaptions = {},
+    aspectRatio: string = '1:1',
+    style?: string
   ): Promise<string> {
     if (!this.connection) {
       const connected = await this.connect();
@@ -268,7 +369,13 @@ class NatsService {
       id,
       prompt,
       fast_mode: fastMode,
-      small_image: smallImage
+      small_image: smallImage,
+      aspect_ratio: aspectRatio,
+      style,
+      top_text: captions.topText || undefined,
+      bottom_text: captions.bottomText || undefined,
+      reply_to: this.clientId,
+      session_id: this.sessionId
     };
 
     // Register callbacks
@@ -278,10 +385,11 @@ class NatsService {
     // Publish request
     if (this.connection) {
       try {
-        // Publish to the exact subject 'meme.request' to match JetStream consumer filter
-        this.connection.publish(this.requestSubject, this.codec.encode(request));
+        // Publish to the request subject (or its fast lane) to match a JetStream consumer filter
+        const subject = fastMode ? this.fastRequestSubject : this.requestSubject;
+        this.connection.publish(subject, this.codec.encode(request));
         console.log(`✅ Sent meme request: ${id} - ${prompt}`, {
-          subject: this.requestSubject,
+          subject,
           requestId: id,
           timestamp: new Date().toISOString()
         });
@@ -296,6 +404,19 @@ class NatsService {
     return id;
   }
 
+  /**
+   * Cancel a pending meme request; its callbacks will not be called
+   */
+  cancelMeme(requestId: string): void {
+    this.responseCallbacks.delete(requestId);
+    this.errorCallbacks.delete(requestId);
+
+    if (this.connection) {
+      this.connection.publish(this.cancelSubject, this.codec.encode({ request_id: requestId }));
+      console.log(`🛑 Cancelled meme request: ${requestId}`);
+    }
+  }
+
 }
 
 // Export as singleton